@@ -0,0 +1,554 @@
+//! Local HTTP/JSON API doing what the slash commands do, for automation.
+//!
+//! On TCP every request carries `Authorization: Bearer <token>`, the token
+//! being [`crate::config::Api::token`]. The Unix socket `voisinc-ctl` talks
+//! to needs none, only its owner may open it. Ids are strings, as in
+//! Discord's own API.
+//!
+//! | request                                        | does                          |
+//! |------------------------------------------------|-------------------------------|
+//! | `GET /v1/bots`                                 | bots of the pool, their calls |
+//! | `GET /v1/calls`                                | every call, as in `/status`   |
+//! | `GET /v1/guilds/{guild}`                       | bots, links and free bots     |
+//! | `DELETE /v1/guilds/{guild}`                    | leave every channel of it     |
+//! | `PUT /v1/guilds/{guild}/channels/{channel}`    | join the channel              |
+//! | `DELETE /v1/guilds/{guild}/channels/{channel}` | leave it, forgetting its links |
+//! | `POST /v1/guilds/{guild}/links`                | link, see [`NewLink`]         |
+//! | `DELETE /v1/guilds/{guild}/links/{from}/{to}`  | unlink                        |
+//! | `GET /v1/guilds/{guild}/volumes`               | per-user gains                |
+//!
+//! Changes answer `204 No Content`, failures a status and
+//! `{"error": kind, "message": text}`.
+
+use std::collections::BTreeSet;
+use std::net::SocketAddr;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::path::Path as FsPath;
+use std::sync::Arc;
+
+use anyhow::Context;
+use axum::extract::{Path, Request, State};
+use axum::http::{header, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get, post, put};
+use axum::{Json, Router};
+use hyper_util::rt::TokioIo;
+use hyper_util::service::TowerToHyperService;
+use serde::{Deserialize, Serialize};
+use serenity::model::id::{ChannelId, GuildId};
+use serenity_voice_model::id::UserId;
+
+use crate::audio::{AudioCommandError, AudioCommandPayload, BotStatus};
+use crate::pool::{BotSummary, Capacity};
+use crate::routing::{Broadcast, Link, LinkMode};
+use crate::types::Data;
+
+/// The routes of the API, requiring `token` if there is one.
+pub fn router(data: Data, token: Option<&str>) -> Router {
+    let routes = Router::new()
+        .route("/v1/bots", get(bots))
+        .route("/v1/calls", get(calls))
+        .route("/v1/guilds/:guild", get(topology).delete(leave_guild))
+        .route("/v1/guilds/:guild/channels/:channel", put(join))
+        .route("/v1/guilds/:guild/channels/:channel", delete(leave))
+        .route("/v1/guilds/:guild/links", post(link))
+        .route("/v1/guilds/:guild/links/:from/:to", delete(unlink))
+        .route("/v1/guilds/:guild/volumes", get(volumes));
+    let routes = match token {
+        Some(token) => {
+            let token: Arc<str> = token.trim().into();
+            routes.layer(middleware::from_fn_with_state(token, authenticate))
+        }
+        None => routes,
+    };
+    routes.with_state(data)
+}
+
+/// Serves `app` on `http://{addr}` until the listener fails.
+pub async fn serve(addr: SocketAddr, app: Router) -> anyhow::Result<()> {
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    tracing::info!("serving the control API on {}", addr);
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+/// Serves `app` on the Unix socket `path`, which only this user may open.
+pub async fn serve_unix(path: &FsPath, app: Router) -> anyhow::Result<()> {
+    // a socket left over from the last run makes bind fail
+    if std::fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+        std::fs::remove_file(path)?;
+    }
+    let listener = bind_private(path)?;
+    tracing::info!("serving the control API on {}", path.display());
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let service = TowerToHyperService::new(app.clone());
+        tokio::spawn(async move {
+            let conn = hyper::server::conn::http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service);
+            if let Err(e) = conn.await {
+                tracing::debug!("control connection failed: {}", e);
+            }
+        });
+    }
+}
+
+/// Binds a socket at `path` that only our user may connect to.
+///
+/// The socket is bound in a directory nobody else may enter and made private
+/// there, then moved into place, so it is never reachable with the umask's
+/// permissions.
+fn bind_private(path: &FsPath) -> anyhow::Result<tokio::net::UnixListener> {
+    let name = path.file_name().context("socket path has no file name")?;
+    let dir = path.with_file_name(format!(
+        ".{}.{}",
+        name.to_string_lossy(),
+        std::process::id()
+    ));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    let tmp = dir.join("sock");
+    let res = (|| -> anyhow::Result<_> {
+        let listener = tokio::net::UnixListener::bind(&tmp)?;
+        std::fs::set_permissions(&tmp, std::fs::Permissions::from_mode(0o600))?;
+        std::fs::rename(&tmp, path)?;
+        Ok(listener)
+    })();
+    let _ = std::fs::remove_dir_all(&dir);
+    res
+}
+
+async fn authenticate(State(expected): State<Arc<str>>, req: Request, next: Next) -> Response {
+    let token = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    match token {
+        Some(token) if same(token.as_bytes(), expected.as_bytes()) => next.run(req).await,
+        _ => failure(
+            StatusCode::UNAUTHORIZED,
+            "Unauthorized",
+            "missing or wrong token".into(),
+        ),
+    }
+}
+
+/// Compares without returning early, so timing does not leak the token.
+fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn failure(status: StatusCode, kind: &str, message: String) -> Response {
+    let body = serde_json::json!({ "error": kind, "message": message });
+    (status, Json(body)).into_response()
+}
+
+struct ApiError(AudioCommandError);
+
+impl From<AudioCommandError> for ApiError {
+    fn from(e: AudioCommandError) -> Self {
+        Self(e)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        use AudioCommandError::*;
+        let status = match self.0 {
+            ChannelNotFound | LinkNotFound | BroadcastNotFound | NotRecording => {
+                StatusCode::NOT_FOUND
+            }
+            Routing(_) | BotUsedFull | AlreadyRecording => StatusCode::CONFLICT,
+            NotPeered => StatusCode::FORBIDDEN,
+            AudioTxNotFound | RecordFailed | ProviderDropped | UnknownError => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        };
+        failure(status, self.0.kind(), self.0.to_string())
+    }
+}
+
+type Result<T = StatusCode> = std::result::Result<T, ApiError>;
+
+#[derive(Serialize)]
+struct Topology {
+    bots: Vec<BotStatus>,
+    links: Vec<Link>,
+    broadcasts: Vec<Broadcast>,
+    capacity: Capacity,
+}
+
+async fn bots(State(data): State<Data>) -> Json<Vec<BotSummary>> {
+    Json(data.pool.bots())
+}
+
+async fn calls(State(data): State<Data>) -> Result<Json<Vec<BotStatus>>> {
+    let guilds: BTreeSet<_> = data
+        .pool
+        .bots()
+        .into_iter()
+        .flat_map(|b| b.calls.into_iter().map(|c| c.guild))
+        .collect();
+    let mut calls = Vec::new();
+    for gid in guilds {
+        calls.extend(data.status(gid).await?);
+    }
+    Ok(Json(calls))
+}
+
+async fn topology(State(data): State<Data>, Path(gid): Path<GuildId>) -> Result<Json<Topology>> {
+    let bots = data.status(gid).await?;
+    let (links, broadcasts) = {
+        let routes = data.routes.lock().await;
+        (routes.links(gid).to_vec(), routes.broadcasts(gid).to_vec())
+    };
+    let capacity = data.pool.capacity(gid);
+    Ok(Json(Topology {
+        bots,
+        links,
+        broadcasts,
+        capacity,
+    }))
+}
+
+async fn join(State(data): State<Data>, Path((gid, cid)): Path<(GuildId, ChannelId)>) -> Result {
+    data.command(AudioCommandPayload::Join(gid, cid)).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn leave(State(data): State<Data>, Path((gid, cid)): Path<(GuildId, ChannelId)>) -> Result {
+    data.command(AudioCommandPayload::Remove(gid, cid)).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Leaves every channel of the guild, going on past failures.
+async fn leave_guild(State(data): State<Data>, Path(gid): Path<GuildId>) -> Result {
+    let mut res = Ok(StatusCode::NO_CONTENT);
+    for bot in data.status(gid).await? {
+        let payload = AudioCommandPayload::Remove(gid, bot.channel);
+        if let Err(e) = data.command(payload).await {
+            tracing::warn!(guild = %gid, channel = %bot.channel, "failed to leave: {}", e);
+            res = Err(e.into());
+        }
+    }
+    res
+}
+
+/// Body of `POST /v1/guilds/{guild}/links`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NewLink {
+    pub from: ChannelId,
+    pub to: ChannelId,
+    /// guild of `to`, if not the same; both guilds must have agreed.
+    #[serde(default)]
+    pub to_guild: Option<GuildId>,
+    #[serde(default)]
+    pub bidirectional: bool,
+}
+
+async fn link(
+    State(data): State<Data>,
+    Path(gid): Path<GuildId>,
+    Json(link): Json<NewLink>,
+) -> Result {
+    let mode = if link.bidirectional {
+        LinkMode::Bidirectional
+    } else {
+        LinkMode::OneWay
+    };
+    let payload = AudioCommandPayload::Connect {
+        gid,
+        from_id: link.from,
+        to_gid: link.to_guild.unwrap_or(gid),
+        to_id: link.to,
+        mode,
+    };
+    data.command(payload).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn unlink(
+    State(data): State<Data>,
+    Path((gid, from_id, to_id)): Path<(GuildId, ChannelId, ChannelId)>,
+) -> Result {
+    let payload = AudioCommandPayload::Disconnect {
+        gid,
+        from_id,
+        to_id,
+    };
+    data.command(payload).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Serialize)]
+struct Volume {
+    user: UserId,
+    db: f32,
+}
+
+async fn volumes(State(data): State<Data>, Path(gid): Path<GuildId>) -> Json<Vec<Volume>> {
+    let mut volumes: Vec<_> = data
+        .volume_map
+        .get(&gid)
+        .map(|map| {
+            map.iter()
+                .map(|v| Volume {
+                    user: *v.key(),
+                    db: v.value().db(),
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    volumes.sort_by_key(|v| v.user);
+    Json(volumes)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use serde_json::{json, Value};
+    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream, UnixStream};
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::audio::{AudioServiceProvider, Gain, GlobalVolumeMap};
+    use crate::pool::BotPool;
+    use crate::routing::SharedRoutingGraph;
+    use crate::sim::{Sim, TempState};
+
+    const TOKEN: &str = "secret";
+
+    /// The API on TCP and on a Unix socket, in front of an audio service
+    /// with two simulated bots.
+    struct Server {
+        addr: SocketAddr,
+        socket: PathBuf,
+        sim: Sim,
+        volume_map: GlobalVolumeMap,
+        _state: TempState,
+    }
+
+    impl Server {
+        async fn start(name: &str) -> Self {
+            let sim = Sim::new();
+            let state = TempState::new(&format!("api-{}", name));
+            let storage = state.open();
+            let (tx, rx) = mpsc::channel(10);
+            let volume_map: GlobalVolumeMap = Default::default();
+            let routes: SharedRoutingGraph = Default::default();
+            let pool = Arc::new(BotPool::new(2));
+            AudioServiceProvider::new(
+                (0..2).map(|bot| sim.driver(bot)).collect(),
+                rx,
+                Arc::clone(&volume_map),
+                Arc::clone(&routes),
+                Default::default(),
+                Arc::clone(&storage),
+                Arc::clone(&pool),
+                false,
+                std::env::temp_dir(),
+            )
+            .run();
+            let data = Data::new(tx, Arc::clone(&volume_map), routes, storage, pool);
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let app = router(data.clone(), Some(TOKEN));
+            tokio::spawn(async move { axum::serve(listener, app).await });
+            let socket = state.path().with_extension("sock");
+            let app = router(data, None);
+            let path = socket.clone();
+            tokio::spawn(async move { serve_unix(&path, app).await });
+            while !socket.exists() {
+                tokio::task::yield_now().await;
+            }
+            Self {
+                addr,
+                socket,
+                sim,
+                volume_map,
+                _state: state,
+            }
+        }
+
+        /// Sends a request with the right token, giving the status and body.
+        async fn send(&self, method: &str, path: &str, body: Option<Value>) -> (u16, Value) {
+            self.send_as(Some(TOKEN), method, path, body).await
+        }
+
+        async fn send_as(
+            &self,
+            token: Option<&str>,
+            method: &str,
+            path: &str,
+            body: Option<Value>,
+        ) -> (u16, Value) {
+            let body = body.map(|b| b.to_string()).unwrap_or_default();
+            let mut req = format!(
+                "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\
+                 Content-Type: application/json\r\nContent-Length: {}\r\n",
+                method,
+                path,
+                body.len()
+            );
+            if let Some(token) = token {
+                req += &format!("Authorization: Bearer {}\r\n", token);
+            }
+            req += "\r\n";
+            req += &body;
+            let stream = TcpStream::connect(self.addr).await.unwrap();
+            exchange(stream, &req).await
+        }
+
+        /// Sends a request on the Unix socket, without a token.
+        async fn send_unix(&self, method: &str, path: &str) -> (u16, Value) {
+            let req = format!(
+                "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+                method, path
+            );
+            let stream = UnixStream::connect(&self.socket).await.unwrap();
+            exchange(stream, &req).await
+        }
+    }
+
+    async fn exchange(mut stream: impl AsyncRead + AsyncWrite + Unpin, req: &str) -> (u16, Value) {
+        stream.write_all(req.as_bytes()).await.unwrap();
+        let mut res = String::new();
+        stream.read_to_string(&mut res).await.unwrap();
+        let status = res[9..12].parse().unwrap();
+        let body = res.split_once("\r\n\r\n").unwrap().1;
+        (status, serde_json::from_str(body).unwrap_or(Value::Null))
+    }
+
+    impl Drop for Server {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.socket);
+        }
+    }
+
+    #[tokio::test]
+    async fn socket_is_private_and_needs_no_token() {
+        let server = Server::start("socket").await;
+        let mode = std::fs::metadata(&server.socket)
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let (status, bots) = server.send_unix("GET", "/v1/bots").await;
+        assert_eq!(status, 200);
+        assert_eq!(
+            bots,
+            json!([
+                { "index": 0, "healthy": false, "calls": [] },
+                { "index": 1, "healthy": false, "calls": [] },
+            ])
+        );
+        server.send("PUT", "/v1/guilds/1/channels/10", None).await;
+        server.send("PUT", "/v1/guilds/2/channels/20", None).await;
+        let (_, calls) = server.send_unix("GET", "/v1/calls").await;
+        let calls: Vec<_> = calls
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|c| (c["guild"].clone(), c["channel"].clone()))
+            .collect();
+        assert_eq!(
+            calls,
+            [(json!("1"), json!("10")), (json!("2"), json!("20"))]
+        );
+
+        assert_eq!(server.send_unix("DELETE", "/v1/guilds/1").await.0, 204);
+        let (_, bots) = server.send_unix("GET", "/v1/bots").await;
+        assert_eq!(bots[0]["calls"], json!([]));
+        assert_eq!(bots[1]["calls"], json!([{ "guild": "2", "channel": "20" }]));
+    }
+
+    #[tokio::test]
+    async fn requires_the_token() {
+        let server = Server::start("token").await;
+        let (status, body) = server.send_as(None, "GET", "/v1/guilds/1", None).await;
+        assert_eq!(status, 401);
+        assert_eq!(body["error"], "Unauthorized");
+        let res = server
+            .send_as(Some("secreT"), "GET", "/v1/guilds/1", None)
+            .await;
+        assert_eq!(res.0, 401);
+        let res = server
+            .send_as(Some(TOKEN), "GET", "/v1/guilds/1", None)
+            .await;
+        assert_eq!(res.0, 200);
+    }
+
+    #[tokio::test]
+    async fn drives_the_bridge() {
+        let server = Server::start("bridge").await;
+        assert_eq!(
+            server.send("PUT", "/v1/guilds/1/channels/10", None).await.0,
+            204
+        );
+        assert_eq!(
+            server.send("PUT", "/v1/guilds/1/channels/11", None).await.0,
+            204
+        );
+        assert_eq!(server.sim.bot_in(ChannelId::new(10)), Some(0));
+
+        let link = json!({ "from": "10", "to": "11", "bidirectional": true });
+        let res = server.send("POST", "/v1/guilds/1/links", Some(link)).await;
+        assert_eq!(res.0, 204);
+        let (status, topology) = server.send("GET", "/v1/guilds/1", None).await;
+        assert_eq!(status, 200);
+        assert_eq!(topology["bots"].as_array().unwrap().len(), 2);
+        assert_eq!(
+            topology["links"],
+            json!([{ "from_id": "10", "to_id": "11", "mode": "bidirectional" }])
+        );
+        assert_eq!(topology["capacity"]["free"], 0);
+
+        let loop_link = json!({ "from": "11", "to": "11" });
+        let (status, body) = server
+            .send("POST", "/v1/guilds/1/links", Some(loop_link))
+            .await;
+        assert_eq!((status, &body["error"]), (409, &json!("Routing")));
+
+        let res = server
+            .send("DELETE", "/v1/guilds/1/links/10/11", None)
+            .await;
+        assert_eq!(res.0, 204);
+        let (status, body) = server
+            .send("DELETE", "/v1/guilds/1/links/10/11", None)
+            .await;
+        assert_eq!((status, &body["error"]), (404, &json!("LinkNotFound")));
+
+        assert_eq!(
+            server
+                .send("DELETE", "/v1/guilds/1/channels/10", None)
+                .await
+                .0,
+            204
+        );
+        assert_eq!(server.sim.bot_in(ChannelId::new(10)), None);
+        assert_eq!(
+            server
+                .send("DELETE", "/v1/guilds/1/channels/10", None)
+                .await
+                .0,
+            404
+        );
+    }
+
+    #[tokio::test]
+    async fn lists_volumes() {
+        let server = Server::start("volumes").await;
+        let map = server.volume_map.entry(GuildId::new(1)).or_default();
+        map.insert(UserId(100), Gain::from_db(-6.0));
+        drop(map);
+        let (status, body) = server.send("GET", "/v1/guilds/1/volumes", None).await;
+        assert_eq!(status, 200);
+        assert_eq!(body, json!([{ "user": "100", "db": -6.0 }]));
+    }
+}
@@ -1,135 +1,247 @@
-use std::collections::HashSet;
-use std::num::NonZeroI16;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
 use std::sync::{Arc, Weak};
+use std::time::Instant;
 
-use dashmap::DashMap;
-use serenity::all::Cache;
+use dashmap::{DashMap, DashSet};
+use prometheus::IntGauge;
+use serde::Serialize;
+use serenity::all::{Context, Ready};
 use serenity::async_trait;
 use serenity::model::id::{ChannelId, GuildId};
 use serenity_voice_model::id::UserId;
-use serenity_voice_model::payload::ClientDisconnect;
-use songbird::input::core::io::MediaSource;
-use songbird::input::core::probe::Hint;
-use songbird::input::{AudioStream, Input, LiveInput};
-use songbird::model::payload::Speaking;
-use songbird::tracks::TrackHandle;
-use songbird::{Call, CoreEvent, Event, EventContext, EventHandler, Songbird};
 use thiserror::Error;
-use tokio::runtime;
-use tokio::sync::oneshot::Sender;
-use tokio::sync::{broadcast, mpsc, Mutex};
+use tokio::sync::oneshot::{self, Sender};
+use tokio::sync::{mpsc, Mutex, Notify};
+use tracing::field::Empty;
+use tracing::{Instrument, Span};
+
+use crate::jitter::JitterConfig;
+use crate::metrics::METRICS;
+use crate::mixer::{Mixer, MixerRx, Voice};
+use crate::passthrough::OpusRx;
+use crate::pool::{Allocation, SharedBotPool};
+use crate::record::{RecordOptions, Recorder, Speaker, Tick};
+use crate::routing::{Broadcast, Link, LinkMode, RoutingError, SharedRoutingGraph, SpeakerFilter};
+use crate::storage::Storage;
+use crate::supervisor::Backoff;
+use crate::voice::{TrackSource, VoiceCall, VoiceDriver, VoiceEvent, VoiceTrack};
 
 #[derive(Debug, Clone)]
 pub struct VoiceEventHandler {
     ssrc_map: Arc<Mutex<Vec<(u32, UserId)>>>,
-    call: Weak<Mutex<Call>>,
+    /// SSRCs heard on the previous tick, to log when speaking starts and stops.
+    speaking: Arc<Mutex<HashSet<u32>>>,
+    call: Arc<dyn VoiceCall>,
     volume_map: VolumeMap,
-    speakers: Arc<Mutex<HashSet<u32>>>,
+    bot_users: BotUsers,
     txs: Arc<Mutex<AudioTx>>,
+    service: Weak<AudioServiceHandler>,
+    gid: GuildId,
+    idx: usize,
+    span: Span,
 }
 
 impl VoiceEventHandler {
     fn new(
-        ssrc_map: Arc<Mutex<Vec<(u32, UserId)>>>,
-        call: Weak<Mutex<Call>>,
+        call: Arc<dyn VoiceCall>,
         volume_map: VolumeMap,
+        bot_users: BotUsers,
         txs: Arc<Mutex<AudioTx>>,
+        service: Weak<AudioServiceHandler>,
+        gid: GuildId,
+        idx: usize,
     ) -> Self {
         Self {
-            ssrc_map,
+            ssrc_map: Default::default(),
+            speaking: Default::default(),
             call,
             volume_map,
+            bot_users,
             txs,
-            speakers: Default::default(),
+            service,
+            gid,
+            idx,
+            span: tracing::info_span!("call", guild = %gid, channel = Empty, bot = idx),
         }
     }
 }
 
-pub type VolumeMap = Arc<DashMap<UserId, NonZeroI16>>;
+pub type VolumeMap = Arc<DashMap<UserId, Gain>>;
 pub type GlobalVolumeMap = Arc<DashMap<GuildId, VolumeMap>>;
+/// Per-user gain in decibels, applied before mixing.
+///
+/// Positive values boost; the mixer's limiter keeps boosted voices from
+/// hard clipping.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Gain(f32);
+
+impl Gain {
+    pub const MIN_DB: f32 = -60.0;
+    pub const MAX_DB: f32 = 20.0;
+
+    pub fn from_db(db: f32) -> Self {
+        Self(db.clamp(Self::MIN_DB, Self::MAX_DB))
+    }
+    pub fn db(self) -> f32 {
+        self.0
+    }
+    /// Linear amplitude multiplier.
+    pub fn factor(self) -> f32 {
+        10f32.powf(self.0 / 20.0)
+    }
+}
+
+impl Default for Gain {
+    fn default() -> Self {
+        Self(0.0)
+    }
+}
+
+/// User ids of the bots in the pool, whose voice is never forwarded.
+pub type BotUsers = Arc<DashSet<UserId>>;
+
+/// Fills [`BotUsers`] as each bot's gateway becomes ready, and signals
+/// `pool_ready` once every bot of the pool is.
+pub struct BotUserCollector {
+    users: BotUsers,
+    pool_size: usize,
+    pool_ready: Arc<Notify>,
+}
+
+impl BotUserCollector {
+    pub fn new(users: BotUsers, pool_size: usize, pool_ready: Arc<Notify>) -> Self {
+        Self {
+            users,
+            pool_size,
+            pool_ready,
+        }
+    }
+}
 
 #[async_trait]
-impl EventHandler for VoiceEventHandler {
-    async fn act(&self, ctx: &EventContext<'_>) -> Option<Event> {
-        match ctx {
+impl serenity::all::EventHandler for BotUserCollector {
+    async fn ready(&self, _: Context, ready: Ready) {
+        self.users.insert(UserId(ready.user.id.get()));
+        if self.users.len() == self.pool_size {
+            self.pool_ready.notify_one();
+        }
+    }
+}
+
+impl VoiceEventHandler {
+    /// Handles `event` of this handler's call.
+    pub async fn event(&self, event: VoiceEvent<'_>) {
+        self.handle(event).instrument(self.span.clone()).await
+    }
+
+    async fn handle(&self, event: VoiceEvent<'_>) {
+        match event {
             // update users ssrc
-            EventContext::SpeakingStateUpdate(Speaking {
-                // speaking,
-                ssrc,
-                user_id,
-                ..
-            }) => {
-                if let Some(uid) = user_id {
-                    self.ssrc_map.lock().await.push((*ssrc, uid.clone()))
-                }
+            VoiceEvent::Speaking { ssrc, user } => {
+                tracing::debug!(ssrc, user = user.0, "ssrc mapped");
+                self.ssrc_map.lock().await.push((ssrc, user))
             }
             // remove users ssrc
-            EventContext::ClientDisconnect(ClientDisconnect { user_id, .. }) => {
+            VoiceEvent::ClientDisconnect { user } => {
                 let mut map = self.ssrc_map.lock().await;
                 let map = &mut *map;
                 for i in (0..map.len()).rev() {
-                    if &map[i].1 == user_id {
+                    if map[i].1 == user {
+                        tracing::debug!(ssrc = map[i].0, user = user.0, "ssrc left");
                         map.remove(i);
                     }
                 }
             }
-            EventContext::VoiceTick(track) => {
-                let mut tx = self.txs.lock().await;
+            VoiceEvent::Tick(voices) => {
+                let mut speech = Vec::new();
+                let mut heard = HashSet::new();
+                let recording = self.txs.lock().await.recorder.is_some();
+                let mut recorded: Tick = Vec::new();
+                {
+                    let ssrc_map = self.ssrc_map.lock().await;
+                    for voice in voices.iter() {
+                        let ssrc = voice.ssrc;
+                        let uid = ssrc_map.iter().find(|(s, _)| *s == ssrc).map(|(_, u)| *u);
+                        let bot = uid.is_some_and(|u| self.bot_users.contains(&u));
+                        if let (true, Some(data)) = (recording, voice.pcm) {
+                            let speaker = match uid {
+                                Some(u) if bot => Speaker::Bridge(u.0),
+                                Some(u) => Speaker::User(u.0),
+                                None => Speaker::Ssrc(ssrc),
+                            };
+                            recorded.push((speaker, data.to_vec()));
+                        }
+                        // a bot's voice is what we played into this channel
+                        if bot {
+                            continue;
+                        }
+                        let Some(data) = voice.pcm else {
+                            continue;
+                        };
+                        heard.insert(ssrc);
+                        let gain = uid
+                            .and_then(|u| self.volume_map.get(&u).map(|x| *x))
+                            .unwrap_or_default();
+                        let opus = voice.opus.clone().filter(|_| gain == Gain::default());
+                        let gain = gain.factor();
+                        speech.push(Speech {
+                            user: uid,
+                            pcm: data.iter().map(|x| (*x as f32 * gain) as i32).collect(),
+                            opus,
+                        });
+                    }
+                }
+                METRICS
+                    .active_ssrcs(self.gid, self.idx)
+                    .set(heard.len() as i64);
                 {
-                    let now_ssrcs: HashSet<u32> = track.speaking.keys().copied().collect();
-                    let mut old_ssrcs = self.speakers.lock().await;
-                    for new_ssrc in now_ssrcs.difference(&*old_ssrcs) {
-                        tx.new_speaking_ssrc(*new_ssrc).await;
+                    let mut speaking = self.speaking.lock().await;
+                    for ssrc in heard.difference(&speaking) {
+                        tracing::debug!(ssrc, "speaking started");
+                    }
+                    for ssrc in speaking.difference(&heard) {
+                        tracing::debug!(ssrc, "speaking stopped");
                     }
-                    for lost_ssrc in old_ssrcs.difference(&now_ssrcs) {
-                        tx.delete_speaking_ssrc(*lost_ssrc);
+                    if *speaking != heard {
+                        let ssrc_map = self.ssrc_map.lock().await;
+                        let users = ssrc_map
+                            .iter()
+                            .filter(|(s, _)| heard.contains(s))
+                            .map(|(_, u)| *u)
+                            .collect();
+                        self.txs.lock().await.speakers = users;
                     }
-                    *old_ssrcs = now_ssrcs;
+                    *speaking = heard;
                 }
-
-                for (&ssrc, data) in track.speaking.iter() {
-                    if let Some(ref data) = data.decoded_voice {
-                        let vol = {
-                            if let Some(uid) = self
-                                .ssrc_map
-                                .lock()
-                                .await
-                                .iter()
-                                .filter(|d| d.0 == ssrc)
-                                .map(|d| d.1)
-                                .next()
-                            {
-                                self.volume_map
-                                    .get(&uid)
-                                    .map(|x| *x)
-                                    .map(Into::into)
-                                    .unwrap_or(1i16)
-                            } else {
-                                1
-                            }
-                        };
-                        let bytes = data.iter().step_by(2).flat_map(|x| {
-                            let t = x / vol;
-                            if cfg!(target_endian = "big") {
-                                t.to_be_bytes()
-                            } else {
-                                t.to_le_bytes()
-                            }
-                        });
-                        tx.send(bytes, ssrc);
+                if recording {
+                    if let Some(recorder) = &self.txs.lock().await.recorder {
+                        recorder.push(recorded);
                     }
                 }
+                if !speech.is_empty() {
+                    self.txs.lock().await.send(&speech);
+                }
             }
-            // EventContext::RtcpPacket(data) => {}
-            // EventContext::RtpPacket(packet) => {}
-            EventContext::DriverDisconnect(_) => {
-                if let Some(c) = self.call.upgrade() {
-                    c.lock().await.remove_all_global_events();
+            VoiceEvent::DriverReconnect { server } => {
+                tracing::info!(server, "driver reconnected");
+            }
+            VoiceEvent::DriverDisconnect { kind, reason } => {
+                tracing::info!(kind, ?reason, "driver disconnected");
+                self.call.unsubscribe().await;
+                // without a reason we were asked to leave, anything else is a failure
+                let failed = reason.is_some();
+                if let Some(service) = self.service.upgrade() {
+                    let (gid, idx, txs) = (self.gid, self.idx, Arc::clone(&self.txs));
+                    tokio::spawn(async move {
+                        let cid = service.drop_call(gid, idx, &txs).await;
+                        if let (Some(cid), true) = (cid, failed) {
+                            service.recover(gid, cid).await;
+                        }
+                    });
                 }
             }
-            _ => (),
         }
-        None
     }
 }
 
@@ -138,435 +250,1812 @@ pub struct AudioServiceProvider {
     handler: Arc<AudioServiceHandler>,
 }
 
+type SharedAudioTx = Arc<Mutex<AudioTx>>;
+
 struct AudioServiceHandler {
-    songbirds: Arc<[Arc<Songbird>]>,
-    cache: Arc<Cache>,
+    drivers: Arc<[Arc<dyn VoiceDriver>]>,
     volume_map: GlobalVolumeMap,
-    txs: Box<[Mutex<Option<Arc<Mutex<AudioTx>>>>]>,
+    /// calls of each bot, a bot having at most one per guild.
+    txs: Box<[Mutex<HashMap<GuildId, SharedAudioTx>>]>,
+    routes: SharedRoutingGraph,
+    bot_users: BotUsers,
+    storage: Arc<Storage>,
+    pool: SharedBotPool,
+    /// channels whose call failed and is being rejoined.
+    recovering: DashSet<(GuildId, ChannelId)>,
+    passthrough: bool,
+    /// where recordings are saved, see [`Recorder`].
+    record_dir: PathBuf,
 }
 
 pub enum AudioCommandPayload {
     Join(GuildId, ChannelId),
     Remove(GuildId, ChannelId),
+    /// leave the channel but keep its saved bridge, e.g. while it is empty.
+    Suspend(GuildId, ChannelId),
+    /// join a suspended channel again and restore its saved links.
+    Resume(GuildId, ChannelId),
+    /// link `from_id` of `gid` to `to_id` of `to_gid`, which is `gid` unless
+    /// both guilds agreed to be linked.
     Connect {
         gid: GuildId,
         from_id: ChannelId,
+        to_gid: GuildId,
         to_id: ChannelId,
+        mode: LinkMode,
     },
     Disconnect {
         gid: GuildId,
         from_id: ChannelId,
         to_id: ChannelId,
     },
+    /// tune the jitter buffer `from_id`'s voice goes through in `to_id`.
+    SetJitter {
+        gid: GuildId,
+        from_id: ChannelId,
+        to_id: ChannelId,
+        config: JitterConfig,
+    },
+    /// choose whose voice of `from_id` is played in `to_id`.
+    SetSpeakers {
+        gid: GuildId,
+        from_id: ChannelId,
+        to_id: ChannelId,
+        filter: SpeakerFilter,
+    },
+    /// start broadcast `name`, joining its `source` channel.
+    StartBroadcast {
+        gid: GuildId,
+        name: String,
+        source: ChannelId,
+    },
+    /// join `cid` and play broadcast `name` in it.
+    AddListener {
+        gid: GuildId,
+        name: String,
+        cid: ChannelId,
+    },
+    /// stop playing broadcast `name` in `cid`.
+    RemoveListener {
+        gid: GuildId,
+        name: String,
+        cid: ChannelId,
+    },
+    EndBroadcast {
+        gid: GuildId,
+        name: String,
+    },
+    /// record the voice heard in `cid`.
+    StartRecording {
+        gid: GuildId,
+        cid: ChannelId,
+        options: RecordOptions,
+    },
+    /// end the recording of `cid`, sending where it was saved to `reply`.
+    StopRecording {
+        gid: GuildId,
+        cid: ChannelId,
+        reply: Sender<PathBuf>,
+    },
+    /// describe the bots of the guild into `reply`.
+    Status {
+        gid: GuildId,
+        reply: Sender<Vec<BotStatus>>,
+    },
+}
+
+/// What one bot of the pool is doing, for `/status`.
+#[derive(Debug, Clone, Serialize)]
+pub struct BotStatus {
+    /// index into the pool, i.e. into `drivers`.
+    pub index: usize,
+    pub guild: GuildId,
+    pub channel: ChannelId,
+    /// channels mixed into this bot's call.
+    pub inbound: Vec<ChannelId>,
+    /// channels this bot's voice is mixed into.
+    pub outbound: Vec<ChannelId>,
+    pub speakers: Vec<UserId>,
+    /// whether the mix is played as passed through Opus.
+    pub opus: bool,
+    pub recording: Option<RecordOptions>,
+}
+impl AudioCommandPayload {
+    pub fn guild_id(&self) -> GuildId {
+        match *self {
+            AudioCommandPayload::Join(gid, _)
+            | AudioCommandPayload::Remove(gid, _)
+            | AudioCommandPayload::Suspend(gid, _)
+            | AudioCommandPayload::Resume(gid, _)
+            | AudioCommandPayload::Connect { gid, .. }
+            | AudioCommandPayload::Disconnect { gid, .. }
+            | AudioCommandPayload::SetJitter { gid, .. }
+            | AudioCommandPayload::SetSpeakers { gid, .. }
+            | AudioCommandPayload::StartBroadcast { gid, .. }
+            | AudioCommandPayload::AddListener { gid, .. }
+            | AudioCommandPayload::RemoveListener { gid, .. }
+            | AudioCommandPayload::EndBroadcast { gid, .. }
+            | AudioCommandPayload::StartRecording { gid, .. }
+            | AudioCommandPayload::StopRecording { gid, .. }
+            | AudioCommandPayload::Status { gid, .. } => gid,
+        }
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            AudioCommandPayload::Join(..) => "join",
+            AudioCommandPayload::Remove(..) => "remove",
+            AudioCommandPayload::Suspend(..) => "suspend",
+            AudioCommandPayload::Resume(..) => "resume",
+            AudioCommandPayload::Connect { .. } => "connect",
+            AudioCommandPayload::Disconnect { .. } => "disconnect",
+            AudioCommandPayload::SetJitter { .. } => "jitter",
+            AudioCommandPayload::SetSpeakers { .. } => "speakers",
+            AudioCommandPayload::StartBroadcast { .. } => "broadcast_start",
+            AudioCommandPayload::AddListener { .. } => "broadcast_add",
+            AudioCommandPayload::RemoveListener { .. } => "broadcast_remove",
+            AudioCommandPayload::EndBroadcast { .. } => "broadcast_end",
+            AudioCommandPayload::StartRecording { .. } => "record_start",
+            AudioCommandPayload::StopRecording { .. } => "record_stop",
+            AudioCommandPayload::Status { .. } => "status",
+        }
+    }
 }
+
 pub struct AudioCommand {
     pub payload: AudioCommandPayload,
     pub tx: Sender<Result<(), AudioCommandError>>,
 }
 
+/// Sends `payload` to the [`AudioServiceProvider`] and waits for its result.
+pub async fn request(
+    commands: &mpsc::Sender<AudioCommand>,
+    payload: AudioCommandPayload,
+) -> Result<(), AudioCommandError> {
+    let (tx, rx) = oneshot::channel();
+    commands
+        .send(AudioCommand { payload, tx })
+        .await
+        .map_err(|_| AudioCommandError::ProviderDropped)?;
+    rx.await.map_err(|_| AudioCommandError::ProviderDropped)?
+}
+
 #[derive(Error, Debug)]
 pub enum AudioCommandError {
     #[error("AudioTx not found")]
     AudioTxNotFound,
     #[error("Channel not found")]
     ChannelNotFound,
+    #[error("Link not found")]
+    LinkNotFound,
+    #[error("Broadcast not found")]
+    BroadcastNotFound,
+    #[error(transparent)]
+    Routing(#[from] RoutingError),
     #[error("All bots joined to channel")]
     BotUsedFull,
+    #[error("Guilds did not both allow linking")]
+    NotPeered,
+    #[error("Channel already recorded")]
+    AlreadyRecording,
+    #[error("Channel not recorded")]
+    NotRecording,
+    #[error("Recording failed")]
+    RecordFailed,
     #[error("AudioServiceProvider doropped")]
     ProviderDropped,
     #[error("Unknown")]
     UnknownError,
 }
 
+impl AudioCommandError {
+    /// Name of the variant, used as a metrics label.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            AudioCommandError::AudioTxNotFound => "AudioTxNotFound",
+            AudioCommandError::ChannelNotFound => "ChannelNotFound",
+            AudioCommandError::LinkNotFound => "LinkNotFound",
+            AudioCommandError::BroadcastNotFound => "BroadcastNotFound",
+            AudioCommandError::Routing(_) => "Routing",
+            AudioCommandError::BotUsedFull => "BotUsedFull",
+            AudioCommandError::NotPeered => "NotPeered",
+            AudioCommandError::AlreadyRecording => "AlreadyRecording",
+            AudioCommandError::NotRecording => "NotRecording",
+            AudioCommandError::RecordFailed => "RecordFailed",
+            AudioCommandError::ProviderDropped => "ProviderDropped",
+            AudioCommandError::UnknownError => "UnknownError",
+        }
+    }
+}
+
 impl AudioServiceProvider {
     #[must_use]
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
-        songbirds: Arc<[Arc<Songbird>]>,
+        drivers: Arc<[Arc<dyn VoiceDriver>]>,
         command_rx: mpsc::Receiver<AudioCommand>,
-        cache: Arc<Cache>,
         volume_map: GlobalVolumeMap,
+        routes: SharedRoutingGraph,
+        bot_users: BotUsers,
+        storage: Arc<Storage>,
+        pool: SharedBotPool,
+        passthrough: bool,
+        record_dir: PathBuf,
     ) -> Self {
         AudioServiceProvider {
             command_rx,
-            handler: Arc::new(AudioServiceHandler::new(songbirds, cache, volume_map)),
+            handler: Arc::new(AudioServiceHandler::new(
+                drivers,
+                volume_map,
+                routes,
+                bot_users,
+                storage,
+                pool,
+                passthrough,
+                record_dir,
+            )),
         }
     }
     pub fn run(mut self) -> tokio::task::JoinHandle<()> {
         tokio::task::spawn(async move {
             while let Some(com) = self.command_rx.recv().await {
                 let handler = Arc::clone(&self.handler);
-                let _ = tokio::task::spawn(async move { handler.handle_command(com).await });
+                let span = tracing::info_span!(
+                    "audio_command",
+                    command = com.payload.name(),
+                    guild = %com.payload.guild_id()
+                );
+                tokio::task::spawn(
+                    async move { handler.handle_command(com).await }.instrument(span),
+                );
             }
         })
     }
 }
 impl AudioServiceHandler {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
-        songbirds: Arc<[Arc<Songbird>]>,
-        cache: Arc<Cache>,
+        drivers: Arc<[Arc<dyn VoiceDriver>]>,
         volume_map: GlobalVolumeMap,
+        routes: SharedRoutingGraph,
+        bot_users: BotUsers,
+        storage: Arc<Storage>,
+        pool: SharedBotPool,
+        passthrough: bool,
+        record_dir: PathBuf,
     ) -> Self {
         Self {
-            cache,
             volume_map,
-            txs: (0..songbirds.len()).map(|_| None).map(Mutex::new).collect(),
-            songbirds,
+            txs: (0..drivers.len()).map(|_| Default::default()).collect(),
+            routes,
+            bot_users,
+            storage,
+            pool,
+            recovering: Default::default(),
+            drivers,
+            passthrough,
+            record_dir,
         }
     }
 
-    pub async fn handle_command(&self, AudioCommand { payload, tx }: AudioCommand) {
+    pub async fn handle_command(self: &Arc<Self>, AudioCommand { payload, tx }: AudioCommand) {
         use AudioCommandPayload::*;
-        match payload {
+        let name = payload.name();
+        let started = Instant::now();
+        let (gid, res) = match payload {
             Join(gid, cid) => {
-                let _ = tx.send(self.join(gid, cid).await);
+                let res = self.join(gid, cid).await;
+                if res.is_ok() {
+                    self.storage.update(|s| s.add_join(gid, cid)).await;
+                }
+                (gid, res)
             }
             Remove(gid, cid) => {
-                let _ = tx.send(self.remove(gid, cid).await);
+                let res = self.remove(gid, cid).await;
+                if res.is_ok() {
+                    let ended = self.routes.lock().await.remove_from_broadcasts(gid, cid);
+                    self.storage
+                        .update(|s| {
+                            s.remove_join(gid, cid);
+                            s.remove_links_of(gid, cid);
+                        })
+                        .await;
+                    // their listeners were only joined to hear the source
+                    for &listener in ended.iter().flat_map(|b| &b.listeners) {
+                        self.release(gid, listener).await;
+                    }
+                }
+                (gid, res)
+            }
+            Suspend(gid, cid) => (gid, self.remove(gid, cid).await),
+            Resume(gid, cid) => {
+                let res = self.join(gid, cid).await;
+                if res.is_ok() {
+                    self.restore_links(gid, cid).await;
+                }
+                (gid, res)
             }
             Connect {
                 gid,
                 from_id,
+                to_gid,
                 to_id,
+                mode,
             } => {
-                let _ = tx.send(self.connect(gid, from_id, to_id).await);
+                let res = self.connect(gid, from_id, to_gid, to_id, mode).await;
+                if res.is_ok() {
+                    let link = Link::new(from_id, to_id, mode);
+                    self.storage
+                        .update(|s| s.add_link(gid, to_gid, &link))
+                        .await;
+                }
+                (gid, res)
             }
             Disconnect {
                 gid,
                 from_id,
                 to_id,
             } => {
-                let _ = tx.send(self.disconnect(gid, from_id, to_id).await);
+                let res = self.disconnect(gid, from_id, to_id).await;
+                if let Ok(link) = &res {
+                    self.storage.update(|s| s.remove_link(gid, link)).await;
+                }
+                (gid, res.map(|_| ()))
             }
-        }
+            SetJitter {
+                gid,
+                from_id,
+                to_id,
+                config,
+            } => (gid, self.set_jitter(gid, from_id, to_id, config).await),
+            SetSpeakers {
+                gid,
+                from_id,
+                to_id,
+                filter,
+            } => (gid, self.set_speakers(gid, from_id, to_id, filter).await),
+            StartBroadcast { gid, name, source } => {
+                let res = self.start_broadcast(gid, &name, source).await;
+                if res.is_ok() {
+                    self.save_broadcast(gid, &name).await;
+                }
+                (gid, res)
+            }
+            AddListener { gid, name, cid } => {
+                let res = self.add_listener(gid, &name, cid).await;
+                if res.is_ok() {
+                    self.save_broadcast(gid, &name).await;
+                }
+                (gid, res)
+            }
+            RemoveListener { gid, name, cid } => {
+                let res = self.remove_listener(gid, &name, cid).await;
+                if res.is_ok() {
+                    self.save_broadcast(gid, &name).await;
+                }
+                (gid, res)
+            }
+            EndBroadcast { gid, name } => {
+                let res = self.end_broadcast(gid, &name).await;
+                if res.is_ok() {
+                    self.storage
+                        .update(|s| s.remove_broadcast(gid, &name))
+                        .await;
+                }
+                (gid, res)
+            }
+            StartRecording { gid, cid, options } => {
+                (gid, self.start_recording(gid, cid, options).await)
+            }
+            StopRecording { gid, cid, reply } => {
+                let res = self.stop_recording(gid, cid).await;
+                let res = res.map(|dir| {
+                    let _ = reply.send(dir);
+                });
+                (gid, res)
+            }
+            Status { gid, reply } => {
+                let _ = reply.send(self.status(gid).await);
+                (gid, Ok(()))
+            }
+        };
+        let outcome = res.as_ref().err().map_or("ok", AudioCommandError::kind);
+        METRICS.command(name, outcome, started.elapsed().as_secs_f64());
+        METRICS.set_links(gid, self.routes.lock().await.links(gid).len());
+        let _ = tx.send(res);
     }
-    async fn join(&self, gid: GuildId, cid: ChannelId) -> Result<(), AudioCommandError> {
-        let Some((idx, unconnected)) = self
-            .songbirds
+    /// Re-creates the saved links of `cid` whose other end has a bot, and
+    /// those of the broadcasts it is in.
+    #[tracing::instrument(skip_all, fields(channel = %cid))]
+    async fn restore_links(&self, gid: GuildId, cid: ChannelId) {
+        let saved = self.storage.state().await.links;
+        for l in saved.iter().filter(|l| l.within(gid) && l.touches(cid)) {
+            let res = self
+                .connect(l.guild, l.from, l.to_guild(), l.to, l.mode())
+                .await;
+            if let Err(e) = res {
+                tracing::debug!("link {} to {} not restored: {}", l.from, l.to, e);
+            }
+        }
+        let broadcast: Vec<Link> = self
+            .routes
+            .lock()
+            .await
+            .broadcasts(gid)
             .iter()
-            .enumerate()
-            .skip_while(|(_, s)| s.get(gid).is_some())
-            .next()
-        else {
-            return Err(AudioCommandError::BotUsedFull);
+            .flat_map(Broadcast::links)
+            .filter(|l| l.touches(cid))
+            .collect();
+        for l in broadcast {
+            let res = self.connect(gid, l.from_id, gid, l.to_id, l.mode).await;
+            if let Err(e) = res {
+                tracing::debug!("broadcast {} to {} not restored: {}", l.from_id, l.to_id, e);
+            }
+        }
+    }
+    #[tracing::instrument(skip_all, fields(channel = %cid, bot))]
+    async fn join(self: &Arc<Self>, gid: GuildId, cid: ChannelId) -> Result<(), AudioCommandError> {
+        let idx = match self.pool.allocate(gid, cid) {
+            Some(Allocation::Joined(idx)) => {
+                Span::current().record("bot", idx);
+                tracing::debug!("already joined");
+                return Ok(());
+            }
+            Some(Allocation::Assigned(idx)) => idx,
+            None => {
+                let capacity = self.pool.capacity(gid);
+                tracing::info!(bots = capacity.total, "no bot free in guild");
+                return Err(AudioCommandError::BotUsedFull);
+            }
         };
-        let Ok(_handler) = unconnected.join(gid, cid).await else {
-            return Err(AudioCommandError::UnknownError);
+        Span::current().record("bot", idx);
+        let call = match self.drivers[idx].join(gid, cid).await {
+            Ok(call) => call,
+            Err(e) => {
+                tracing::warn!("failed to join: {}", e);
+                self.pool.release(idx, gid, cid);
+                return Err(AudioCommandError::UnknownError);
+            }
         };
-        let txs = AudioTx::mutex(5, Arc::clone(&self.songbirds), cid, Arc::clone(&self.cache));
-        let volume_map;
-        let vm_is_none;
-        {
-            let vm = &self.volume_map.get(&gid);
-            volume_map = match vm {
-                Some(volume_map) => Arc::clone(volume_map),
-                None => Default::default(),
-            };
-            vm_is_none = vm.is_none();
-        }
-        if vm_is_none {
-            self.volume_map.insert(gid, Arc::clone(&volume_map));
-        }
+        let txs = AudioTx::mutex(
+            gid,
+            cid,
+            Arc::clone(&call),
+            METRICS.tracks_playing(gid, idx),
+            self.passthrough,
+        );
+        let volume_map = Arc::clone(&self.volume_map.entry(gid).or_default());
         let event_handler = VoiceEventHandler::new(
-            Default::default(),
-            Arc::downgrade(&_handler),
+            Arc::clone(&call),
             volume_map,
+            Arc::clone(&self.bot_users),
             Arc::clone(&txs),
+            Arc::downgrade(self),
+            gid,
+            idx,
         );
-        let events = [
-            CoreEvent::SpeakingStateUpdate,
-            CoreEvent::ClientDisconnect,
-            CoreEvent::VoiceTick,
-            CoreEvent::RtcpPacket,
-            CoreEvent::RtpPacket,
-            CoreEvent::DriverDisconnect,
-        ];
-        let mut handler_lock = _handler.lock().await;
-        for e in events {
-            handler_lock.add_global_event(Event::Core(e), event_handler.clone())
-        }
-
-        let mut x = self.txs[idx].lock().await;
-        *x = Some(txs);
+        event_handler
+            .span
+            .record("channel", tracing::field::display(cid));
+        call.subscribe(event_handler).await;
+
+        self.txs[idx].lock().await.insert(gid, txs);
+        tracing::info!("joined");
         Ok(())
     }
-    #[tracing::instrument(skip(self))]
+    #[tracing::instrument(skip_all, fields(channel = %cid, bot))]
     async fn remove(&self, gid: GuildId, cid: ChannelId) -> Result<(), AudioCommandError> {
-        for s in self.songbirds.iter() {
-            if s.get(gid).is_some() {
-                if let Err(e) = s.remove(gid).await {
-                    tracing::warn!("call remove error: {}", e);
-                };
+        let Some(idx) = self.pool.bot_in(gid, cid) else {
+            // leaving a call that is being rejoined only stops the rejoining
+            if self.recovering.remove(&(gid, cid)).is_some() {
+                tracing::info!("recovery cancelled");
+                return Ok(());
             }
-        }
+            return Err(AudioCommandError::ChannelNotFound);
+        };
+        Span::current().record("bot", idx);
+        // forget the call first, so its disconnect event finds nothing to clean up
+        self.txs[idx].lock().await.remove(&gid);
+        if let Err(e) = self.drivers[idx].leave(gid).await {
+            tracing::warn!("call remove error: {}", e);
+        };
+        self.unroute_call(gid, idx, cid).await;
+        self.pool.release(idx, gid, cid);
+        tracing::info!("left");
         Ok(())
     }
+    /// Forgets the call of bot `idx` after its driver went away, giving its
+    /// channel unless it was forgotten already.
+    #[tracing::instrument(skip_all, fields(guild = %gid, bot = idx))]
+    async fn drop_call(&self, gid: GuildId, idx: usize, txs: &SharedAudioTx) -> Option<ChannelId> {
+        {
+            let mut slot = self.txs[idx].lock().await;
+            // the slot may already hold a newer call of the same bot
+            if !slot.get(&gid).is_some_and(|t| Arc::ptr_eq(t, txs)) {
+                return None;
+            }
+            slot.remove(&gid);
+        }
+        let cid = txs.lock().await.channel_id;
+        tracing::warn!(channel = %cid, "call dropped");
+        self.unroute_call(gid, idx, cid).await;
+        self.pool.release(idx, gid, cid);
+        METRICS.set_links(gid, self.routes.lock().await.links(gid).len());
+        if let Err(e) = self.drivers[idx].leave(gid).await {
+            tracing::debug!("call remove error: {}", e);
+        }
+        Some(cid)
+    }
+    /// Rejoins `cid` after its call failed, with backoff, and restores its
+    /// saved links once back.
+    ///
+    /// Any free bot may take the channel over. Leaving the channel meanwhile
+    /// stops the attempts; after the last one the channel is left to the
+    /// [`VoiceStateWatcher`](crate::watcher::VoiceStateWatcher).
+    #[tracing::instrument(skip_all, fields(guild = %gid, channel = %cid))]
+    async fn recover(self: &Arc<Self>, gid: GuildId, cid: ChannelId) {
+        let key = (gid, cid);
+        self.recovering.insert(key);
+        let mut backoff = Backoff::new();
+        while let Some(delay) = backoff.next() {
+            tokio::time::sleep(delay).await;
+            if !self.recovering.contains(&key) {
+                return;
+            }
+            match self.join(gid, cid).await {
+                Ok(()) => {
+                    if self.recovering.remove(&key).is_none() {
+                        // left while joining
+                        let _ = self.remove(gid, cid).await;
+                        return;
+                    }
+                    self.restore_links(gid, cid).await;
+                    METRICS.reconnect(gid, "recovered");
+                    tracing::info!(attempt = backoff.attempt(), "call recovered");
+                    return;
+                }
+                Err(e) => {
+                    METRICS.reconnect(gid, "failed");
+                    tracing::warn!(attempt = backoff.attempt(), "failed to rejoin: {}", e);
+                }
+            }
+        }
+        if self.recovering.remove(&key).is_some() {
+            METRICS.reconnect(gid, "gave_up");
+            tracing::error!("gave up rejoining");
+        }
+    }
+    /// Stops mixing into and out of the call of bot `idx`, which was in `cid`.
+    async fn unroute_call(&self, gid: GuildId, idx: usize, cid: ChannelId) {
+        // links may cross guilds, so look at every call left
+        for tx in self.calls().await {
+            let mut tx = tx.lock().await;
+            tx.disconnect_to(cid);
+            tx.detach(cid).await;
+        }
+        self.routes.lock().await.remove_channel(gid, cid);
+        METRICS.forget_call(gid, idx);
+    }
+    #[tracing::instrument(skip_all, fields(from = %from_id, to_guild = %to_gid, to = %to_id, ?mode))]
     async fn connect(
         &self,
         gid: GuildId,
         from_id: ChannelId,
+        to_gid: GuildId,
         to_id: ChannelId,
+        mode: LinkMode,
     ) -> Result<(), AudioCommandError> {
-        let to_idx = get_songbird_index_by_channel_id(&self.songbirds, gid, to_id).await;
-        let from_idx = get_songbird_index_by_channel_id(&self.songbirds, gid, from_id).await;
-        match (from_idx, to_idx) {
-            (Some(from), Some(to)) if from != to => {
-                let tx = self.txs[from].lock().await;
-                if let Some(tx) = tx.as_ref() {
-                    tx.lock().await.connect_to(to);
-                    Ok(())
-                } else {
-                    Err(AudioCommandError::AudioTxNotFound)
+        if !self.storage.peered(gid, to_gid).await {
+            return Err(AudioCommandError::NotPeered);
+        }
+        // a peer's channel is only reachable through its own guild
+        for (g, c) in [(gid, from_id), (to_gid, to_id)] {
+            let tx = self
+                .call_in(c)
+                .await
+                .ok_or(AudioCommandError::ChannelNotFound)?;
+            if tx.lock().await.guild_id != g {
+                return Err(AudioCommandError::ChannelNotFound);
+            }
+        }
+        let link = Link::new(from_id, to_id, mode);
+        let mut routes = self.routes.lock().await;
+        let Some(superseded) = routes.insert_between(gid, to_gid, link.clone())? else {
+            return Ok(());
+        };
+        let directions = link.directions();
+        for (i, &(from, to)) in directions.iter().enumerate() {
+            if let Err(e) = self.route(from, to, true).await {
+                // don't leave the first direction of a failed bidirectional link behind
+                for &(from, to) in &directions[..i] {
+                    let _ = self.route(from, to, false).await;
+                }
+                routes.remove(gid, from_id, to_id);
+                for old in superseded {
+                    for (from, to) in old.directions() {
+                        let _ = self.route(from, to, true).await;
+                    }
+                    // superseded links join the same two channels, maybe reversed
+                    let (f, t) = if old.from_id == from_id {
+                        (gid, to_gid)
+                    } else {
+                        (to_gid, gid)
+                    };
+                    let _ = routes.insert_between(f, t, old);
                 }
+                return Err(e);
             }
-            _ => return Err(AudioCommandError::ChannelNotFound),
         }
+        tracing::info!("linked");
+        Ok(())
     }
+    #[tracing::instrument(skip_all, fields(from = %from_id, to = %to_id))]
     async fn disconnect(
         &self,
         gid: GuildId,
         from_id: ChannelId,
         to_id: ChannelId,
+    ) -> Result<Link, AudioCommandError> {
+        let link = {
+            let mut routes = self.routes.lock().await;
+            if routes.in_broadcast(gid, from_id, to_id) {
+                return Err(RoutingError::Broadcast.into());
+            }
+            routes
+                .remove(gid, from_id, to_id)
+                .ok_or(AudioCommandError::LinkNotFound)?
+        };
+        for (from, to) in link.directions() {
+            if let Err(e) = self.route(from, to, false).await {
+                tracing::warn!("failed to tear down route: {}", e);
+            }
+        }
+        tracing::info!("unlinked");
+        Ok(link)
+    }
+    #[tracing::instrument(skip_all, fields(from = %from_id, to = %to_id, ?config))]
+    async fn set_jitter(
+        &self,
+        gid: GuildId,
+        from_id: ChannelId,
+        to_id: ChannelId,
+        config: JitterConfig,
     ) -> Result<(), AudioCommandError> {
-        let to_idx = get_songbird_index_by_channel_id(&self.songbirds, gid, to_id).await;
-        let from_idx = get_songbird_index_by_channel_id(&self.songbirds, gid, from_id).await;
-        match (from_idx, to_idx) {
-            (Some(from), Some(to)) if from != to => {
-                let tx = self.txs[from].lock().await;
-                if let Some(tx) = tx.as_ref() {
-                    tx.lock().await.disconnect_to(to);
-                    Ok(())
-                } else {
-                    Err(AudioCommandError::AudioTxNotFound)
-                }
+        let linked = self
+            .routes
+            .lock()
+            .await
+            .links(gid)
+            .iter()
+            .any(|l| l.routes(from_id, to_id));
+        if !linked {
+            return Err(AudioCommandError::LinkNotFound);
+        }
+        self.storage
+            .update(|s| s.set_jitter(gid, from_id, to_id, config))
+            .await;
+        // a live mixer picks it up right away, otherwise `route` applies it
+        if let Some(dest) = self.call_in(to_id).await {
+            if let Some(mixer) = dest.lock().await.mixer() {
+                mixer.set_jitter(from_id, config);
             }
-            _ => return Err(AudioCommandError::ChannelNotFound),
         }
+        Ok(())
     }
-}
-
-async fn get_songbird_index_by_channel_id(
-    songbirds: &[Arc<Songbird>],
-    gid: GuildId,
-    cid: ChannelId,
-) -> Option<usize> {
-    for (idx, s) in songbirds.iter().enumerate() {
-        if let Some(c) = s.get(gid) {
-            if c.lock().await.current_channel() == Some(cid.into()) {
-                return Some(idx);
+    #[tracing::instrument(skip_all, fields(from = %from_id, to = %to_id, ?filter))]
+    async fn set_speakers(
+        &self,
+        gid: GuildId,
+        from_id: ChannelId,
+        to_id: ChannelId,
+        filter: SpeakerFilter,
+    ) -> Result<(), AudioCommandError> {
+        let linked = self
+            .routes
+            .lock()
+            .await
+            .links(gid)
+            .iter()
+            .any(|l| l.routes(from_id, to_id));
+        if !linked {
+            return Err(AudioCommandError::LinkNotFound);
+        }
+        self.storage
+            .update(|s| s.set_speakers(gid, from_id, to_id, filter.clone()))
+            .await;
+        if let Some(source) = self.call_in(from_id).await {
+            source.lock().await.filter(to_id, filter);
+        }
+        Ok(())
+    }
+    #[tracing::instrument(skip_all, fields(name = %name, source = %source))]
+    async fn start_broadcast(
+        self: &Arc<Self>,
+        gid: GuildId,
+        name: &str,
+        source: ChannelId,
+    ) -> Result<(), AudioCommandError> {
+        self.routes
+            .lock()
+            .await
+            .start_broadcast(gid, name, source)?;
+        if let Err(e) = self.join(gid, source).await {
+            self.routes.lock().await.end_broadcast(gid, name);
+            return Err(e);
+        }
+        self.storage.update(|s| s.add_join(gid, source)).await;
+        tracing::info!("broadcast started");
+        Ok(())
+    }
+    /// Joins `cid` with a free bot and plays the broadcast in it, right away
+    /// if the source has a bot or else once it is resumed.
+    #[tracing::instrument(skip_all, fields(name = %name, channel = %cid))]
+    async fn add_listener(
+        self: &Arc<Self>,
+        gid: GuildId,
+        name: &str,
+        cid: ChannelId,
+    ) -> Result<(), AudioCommandError> {
+        self.routes
+            .lock()
+            .await
+            .add_listener(gid, name, cid)
+            .ok_or(AudioCommandError::BroadcastNotFound)??;
+        if let Err(e) = self.join(gid, cid).await {
+            self.routes.lock().await.remove_listener(gid, name, cid);
+            return Err(e);
+        }
+        self.storage.update(|s| s.add_join(gid, cid)).await;
+        // ended while joining
+        let Some(source) = self
+            .routes
+            .lock()
+            .await
+            .broadcast(gid, name)
+            .map(|b| b.source)
+        else {
+            self.release(gid, cid).await;
+            return Err(AudioCommandError::BroadcastNotFound);
+        };
+        if self.pool.bot_in(gid, source).is_some() {
+            let res = self.connect(gid, source, gid, cid, LinkMode::OneWay).await;
+            if let Err(e) = res {
+                self.routes.lock().await.remove_listener(gid, name, cid);
+                self.release(gid, cid).await;
+                return Err(e);
+            }
+        }
+        tracing::info!("listener added");
+        Ok(())
+    }
+    #[tracing::instrument(skip_all, fields(name = %name, channel = %cid))]
+    async fn remove_listener(
+        &self,
+        gid: GuildId,
+        name: &str,
+        cid: ChannelId,
+    ) -> Result<(), AudioCommandError> {
+        let source = {
+            let mut routes = self.routes.lock().await;
+            let source = routes
+                .broadcast(gid, name)
+                .ok_or(AudioCommandError::BroadcastNotFound)?
+                .source;
+            if !routes.remove_listener(gid, name, cid) {
+                return Err(AudioCommandError::ChannelNotFound);
             }
+            source
+        };
+        self.unlink_broadcast(gid, source, cid).await;
+        self.release(gid, cid).await;
+        tracing::info!("listener removed");
+        Ok(())
+    }
+    #[tracing::instrument(skip_all, fields(name = %name))]
+    async fn end_broadcast(&self, gid: GuildId, name: &str) -> Result<(), AudioCommandError> {
+        let broadcast = self
+            .routes
+            .lock()
+            .await
+            .end_broadcast(gid, name)
+            .ok_or(AudioCommandError::BroadcastNotFound)?;
+        for &cid in &broadcast.listeners {
+            self.unlink_broadcast(gid, broadcast.source, cid).await;
+            self.release(gid, cid).await;
         }
+        self.release(gid, broadcast.source).await;
+        tracing::info!("broadcast ended");
+        Ok(())
+    }
+    /// Stops playing `source` in `cid`, which has left its broadcast.
+    async fn unlink_broadcast(&self, gid: GuildId, source: ChannelId, cid: ChannelId) {
+        match self.disconnect(gid, source, cid).await {
+            // not linked while either end is suspended
+            Ok(_) | Err(AudioCommandError::LinkNotFound) => {}
+            Err(e) => tracing::warn!("failed to unlink {} from {}: {}", cid, source, e),
+        }
+    }
+    /// Leaves `cid`, joined for a broadcast, once nothing is routed through it.
+    async fn release(&self, gid: GuildId, cid: ChannelId) {
+        {
+            let routes = self.routes.lock().await;
+            let linked = routes.links(gid).iter().any(|l| l.touches(cid));
+            if linked || routes.broadcasts(gid).iter().any(|b| b.touches(cid)) {
+                return;
+            }
+        }
+        if self.remove(gid, cid).await.is_ok() {
+            self.storage.update(|s| s.remove_join(gid, cid)).await;
+        }
+    }
+    async fn save_broadcast(&self, gid: GuildId, name: &str) {
+        let broadcast = self.routes.lock().await.broadcast(gid, name).cloned();
+        if let Some(broadcast) = broadcast {
+            self.storage
+                .update(|s| s.set_broadcast(gid, &broadcast))
+                .await;
+        }
+    }
+    async fn status(&self, gid: GuildId) -> Vec<BotStatus> {
+        let mut bots = Vec::new();
+        for (index, slot) in self.txs.iter().enumerate() {
+            let Some(tx) = slot.lock().await.get(&gid).cloned() else {
+                continue;
+            };
+            let tx = tx.lock().await;
+            bots.push(BotStatus {
+                index,
+                guild: gid,
+                channel: tx.channel_id,
+                inbound: tx.sources.iter().copied().collect(),
+                outbound: tx.reception.keys().copied().collect(),
+                speakers: tx.speakers.clone(),
+                opus: tx.output.is_some() && tx.opus_track,
+                recording: tx.recorder.as_ref().map(Recorder::options),
+            });
+        }
+        bots
+    }
+    #[tracing::instrument(skip_all, fields(channel = %cid, ?options))]
+    async fn start_recording(
+        &self,
+        gid: GuildId,
+        cid: ChannelId,
+        options: RecordOptions,
+    ) -> Result<(), AudioCommandError> {
+        let tx = self
+            .call_in(cid)
+            .await
+            .ok_or(AudioCommandError::ChannelNotFound)?;
+        let mut tx = tx.lock().await;
+        // another guild's channel can't be recorded from here
+        if tx.guild_id != gid {
+            return Err(AudioCommandError::ChannelNotFound);
+        }
+        if tx.recorder.is_some() {
+            return Err(AudioCommandError::AlreadyRecording);
+        }
+        let recorder = Recorder::start(&self.record_dir, gid, cid, options).map_err(|e| {
+            tracing::warn!("failed to start recording: {}", e);
+            AudioCommandError::RecordFailed
+        })?;
+        tracing::info!(dir = %recorder.dir().display(), "recording started");
+        tx.recorder = Some(recorder);
+        Ok(())
+    }
+    /// Ends the recording of `cid` and waits for its files to be closed.
+    #[tracing::instrument(skip_all, fields(channel = %cid))]
+    async fn stop_recording(
+        &self,
+        gid: GuildId,
+        cid: ChannelId,
+    ) -> Result<PathBuf, AudioCommandError> {
+        let tx = self
+            .call_in(cid)
+            .await
+            .ok_or(AudioCommandError::NotRecording)?;
+        let mut tx = tx.lock().await;
+        if tx.guild_id != gid {
+            return Err(AudioCommandError::NotRecording);
+        }
+        let recorder = tx.recorder.take().ok_or(AudioCommandError::NotRecording)?;
+        drop(tx);
+        let dir = recorder.dir().to_owned();
+        let thread = recorder.finish();
+        match tokio::task::spawn_blocking(move || thread.join()).await {
+            Ok(Ok(Ok(()))) => Ok(dir),
+            Ok(Ok(Err(e))) => {
+                tracing::warn!("recording failed: {}", e);
+                Err(AudioCommandError::RecordFailed)
+            }
+            _ => Err(AudioCommandError::RecordFailed),
+        }
+    }
+    /// Starts or stops forwarding voice of `from_id` into `to_id`, which may
+    /// be channels of different guilds.
+    async fn route(
+        &self,
+        from_id: ChannelId,
+        to_id: ChannelId,
+        on: bool,
+    ) -> Result<(), AudioCommandError> {
+        let (Some(source), Some(dest)) = (self.call_in(from_id).await, self.call_in(to_id).await)
+        else {
+            return Err(AudioCommandError::ChannelNotFound);
+        };
+        if on {
+            let mut dest = dest.lock().await;
+            let mixer = dest
+                .attach(from_id)
+                .await
+                .ok_or(AudioCommandError::AudioTxNotFound)?;
+            let config = self.storage.jitter(dest.guild_id, from_id, to_id).await;
+            mixer.set_jitter(from_id, config);
+            let filter = self.storage.speakers(from_id, to_id).await;
+            let mut source = source.lock().await;
+            source.connect_to(to_id, mixer);
+            source.filter(to_id, filter);
+        } else {
+            source.lock().await.disconnect_to(to_id);
+            dest.lock().await.detach(from_id).await;
+        }
+        Ok(())
+    }
+    /// Every call of every bot.
+    async fn calls(&self) -> Vec<SharedAudioTx> {
+        let mut calls = Vec::new();
+        for slot in self.txs.iter() {
+            calls.extend(slot.lock().await.values().cloned());
+        }
+        calls
+    }
+    /// The call of whichever bot is in `cid`, in any guild.
+    async fn call_in(&self, cid: ChannelId) -> Option<SharedAudioTx> {
+        for tx in self.calls().await {
+            if tx.lock().await.channel_id == cid {
+                return Some(tx);
+            }
+        }
+        None
     }
-    None
 }
 
 #[derive(Debug)]
-pub struct AutoStopTrackHandle(pub TrackHandle);
+pub struct AutoStopTrackHandle(pub Box<dyn VoiceTrack>);
 
 impl Drop for AutoStopTrackHandle {
     fn drop(&mut self) {
-        eprintln!("track stoped");
-        let _ = self.0.stop();
+        tracing::debug!(track = self.0.id(), "track stopped");
+        self.0.stop();
     }
 }
 
 #[derive(Debug)]
 struct AudioTx {
-    txs: Vec<(u32, broadcast::Sender<Arc<[u8]>>)>,
-    /// if conntracs[] is Some then it's a voice connection.
-    reception_tracks: Box<[Option<Vec<(u32, AutoStopTrackHandle)>>]>,
-    songbirds: Arc<[Arc<Songbird>]>,
-    buf_size: usize,
+    /// mixers of the calls this channel's voice is played in, by their channel.
+    reception: HashMap<ChannelId, Arc<Mixer>>,
+    /// whose voice is played in each of `reception`, everyone's if missing.
+    filters: HashMap<ChannelId, SpeakerFilter>,
+    /// channels mixed into this call, with the track playing them.
+    sources: HashSet<ChannelId>,
+    output: Option<(Arc<Mixer>, AutoStopTrackHandle)>,
+    guild_id: GuildId,
     channel_id: ChannelId,
-    cache: Arc<Cache>,
+    call: Arc<dyn VoiceCall>,
+    tracks: IntGauge,
+    /// play a lone source as Opus, see [`OpusRx`].
+    passthrough: bool,
+    opus_track: bool,
+    /// users heard on the last tick.
+    speakers: Vec<UserId>,
+    /// records what is heard in this channel while set.
+    recorder: Option<Recorder>,
 }
 
 impl AudioTx {
     pub fn new(
-        buf_size: usize,
-        driver: Arc<[Arc<Songbird>]>,
+        guild_id: GuildId,
         channel_id: ChannelId,
-        cache: Arc<Cache>,
+        call: Arc<dyn VoiceCall>,
+        tracks: IntGauge,
+        passthrough: bool,
     ) -> Self {
         Self {
-            txs: Default::default(),
-            reception_tracks: (0..driver.len()).map(|_| None).collect(),
-            songbirds: driver,
-            buf_size,
+            reception: HashMap::new(),
+            filters: HashMap::new(),
+            sources: Default::default(),
+            output: None,
+            guild_id,
             channel_id,
-            cache,
+            call,
+            tracks,
+            passthrough,
+            opus_track: false,
+            speakers: Vec::new(),
+            recorder: None,
         }
     }
 
     pub fn mutex(
-        buf_size: usize,
-        driver: Arc<[Arc<Songbird>]>,
+        guild_id: GuildId,
         channel_id: ChannelId,
-        cache: Arc<Cache>,
+        call: Arc<dyn VoiceCall>,
+        tracks: IntGauge,
+        passthrough: bool,
     ) -> Arc<Mutex<Self>> {
-        Arc::new(Mutex::new(Self::new(buf_size, driver, channel_id, cache)))
+        Arc::new(Mutex::new(Self::new(
+            guild_id,
+            channel_id,
+            call,
+            tracks,
+            passthrough,
+        )))
     }
 
-    pub fn connect_to(&mut self, connect_to: usize) {
-        self.reception_tracks[connect_to] = Some(Default::default());
-        dbg!(self);
+    pub fn connect_to(&mut self, connect_to: ChannelId, mixer: Arc<Mixer>) {
+        self.reception.insert(connect_to, mixer);
     }
 
-    pub fn disconnect_to(&mut self, disconnect_to: usize) {
-        self.reception_tracks[disconnect_to] = None;
+    /// Plays only the speakers `filter` admits in `to`.
+    pub fn filter(&mut self, to: ChannelId, filter: SpeakerFilter) {
+        if filter.is_empty() {
+            self.filters.remove(&to);
+        } else {
+            self.filters.insert(to, filter);
+        }
     }
 
-    pub async fn new_speaking_ssrc(&mut self, ssrc: u32) {
-        let guild_id;
-        let bit_rate;
-        {
-            let guild = self.cache.channel(self.channel_id).expect("no guild");
-            guild_id = guild.guild_id;
-            bit_rate = guild.bitrate.unwrap_or(64 * 1000);
+    pub fn disconnect_to(&mut self, disconnect_to: ChannelId) {
+        self.filters.remove(&disconnect_to);
+        if let Some(mixer) = self.reception.remove(&disconnect_to) {
+            mixer.remove_input(self.channel_id);
         }
-        let mut calls = Vec::new();
-        for s in self.songbirds.iter() {
-            calls.push(s.get(guild_id));
+    }
+
+    /// Adds `source` to this call's mix, starting the mix track if needed.
+    pub async fn attach(&mut self, source: ChannelId) -> Option<Arc<Mixer>> {
+        self.sources.insert(source);
+        match &self.output {
+            None => {
+                let mixer = Mixer::new(self.guild_id, self.channel_id);
+                let track = self.play(&mixer).await?;
+                self.output = Some((mixer, track));
+                self.tracks.inc();
+            }
+            Some(_) => self.switch_track().await,
         }
+        self.mixer()
+    }
 
-        let (tx, _) = broadcast::channel(self.buf_size);
-        eprintln!("bitrate:{bit_rate}");
-        for (index, handle_map) in self
-            .reception_tracks
-            .iter_mut()
-            .enumerate()
-            .filter_map(|(i, x)| x.as_mut().map(|h| (i, h)))
-        {
-            eprintln!("add track: {index}");
-            if let Some(Some(call)) = calls.get(index) {
-                let track = call
-                    .lock()
-                    .await
-                    .play_input(AudioRx::new_input(&tx, bit_rate as _));
-                handle_map.push((ssrc, AutoStopTrackHandle(track)));
-            }
-        }
-        self.txs.push((ssrc, tx));
-    }
-    pub fn delete_speaking_ssrc(&mut self, ssrc: u32) {
-        self.reception_tracks.iter_mut().for_each(|rt| {
-            if let Some(ssrc_map) = rt {
-                ssrc_map
-                    .iter()
-                    .position(|(x, _)| *x == ssrc)
-                    .map(|pos| ssrc_map.remove(pos));
-            }
-            self.txs
-                .iter()
-                .position(|(x, _)| *x == ssrc)
-                .map(|pos| self.txs.remove(pos));
-        });
-    }
-
-    pub fn send(&self, voice: impl IntoIterator<Item = u8>, ssrc: u32) {
-        if let Some((_, tx)) = self.txs.iter().find(|(x, _)| *x == ssrc) {
-            if tx.receiver_count() == 0 {
-                return;
+    /// Whether the mix should be played as passed through Opus.
+    fn wants_opus(&self) -> bool {
+        self.passthrough && self.sources.len() == 1
+    }
+
+    /// Starts a track playing `mixer`, as Opus if it has a single source.
+    async fn play(&mut self, mixer: &Arc<Mixer>) -> Option<AutoStopTrackHandle> {
+        let source = match self.wants_opus().then(|| OpusRx::new(Arc::clone(mixer))) {
+            Some(Ok(rx)) => TrackSource::Opus(rx),
+            Some(Err(e)) => {
+                tracing::warn!("no opus passthrough: {}", e);
+                TrackSource::Pcm(MixerRx::new(Arc::clone(mixer)))
+            }
+            None => TrackSource::Pcm(MixerRx::new(Arc::clone(mixer))),
+        };
+        let opus = matches!(source, TrackSource::Opus(_));
+        let track = self.call.play(source).await?;
+        tracing::debug!(channel = %self.channel_id, track = track.id(), opus, "track started");
+        self.opus_track = opus;
+        Some(AutoStopTrackHandle(track))
+    }
+
+    /// Replaces the track when the number of sources crosses one.
+    async fn switch_track(&mut self) {
+        if self.opus_track == self.wants_opus() {
+            return;
+        }
+        let Some(mixer) = self.mixer() else {
+            return;
+        };
+        if let Some(track) = self.play(&mixer).await {
+            // dropping the old handle stops it
+            self.output = Some((mixer, track));
+        }
+    }
+
+    /// The mixer of this call, if anything is routed into it.
+    pub fn mixer(&self) -> Option<Arc<Mixer>> {
+        self.output.as_ref().map(|(mixer, _)| Arc::clone(mixer))
+    }
+
+    /// Removes `source` from this call's mix, stopping the track once it is empty.
+    pub async fn detach(&mut self, source: ChannelId) {
+        if !self.sources.remove(&source) {
+            return;
+        }
+        if let Some((mixer, _)) = &self.output {
+            mixer.remove_input(source);
+        }
+        if self.sources.is_empty() {
+            if let Some((mixer, _)) = self.output.take() {
+                mixer.close();
+                self.tracks.dec();
+            }
+        } else {
+            self.switch_track().await;
+        }
+    }
+
+    /// Mixes what each destination may hear of `speech` and queues it there.
+    ///
+    /// Destinations admitting the same speakers share one mix; one that
+    /// admits nobody gets nothing.
+    pub fn send(&self, speech: &[Speech]) {
+        let mut mixes: Vec<(Vec<usize>, Option<Voice>)> = Vec::new();
+        for (to, mixer) in &self.reception {
+            let filter = self.filters.get(to);
+            let admitted: Vec<usize> = (0..speech.len())
+                .filter(|&i| filter.is_none_or(|f| f.admits(speech[i].user)))
+                .collect();
+            let voice = match mixes.iter().find(|(a, _)| *a == admitted) {
+                Some((_, voice)) => voice.clone(),
+                None => {
+                    let voice = Speech::mix(admitted.iter().map(|&i| &speech[i]));
+                    mixes.push((admitted, voice.clone()));
+                    voice
+                }
+            };
+            if let Some(voice) = voice {
+                mixer.push(self.channel_id, voice);
             }
-            let value = voice.into_iter().collect();
-            let _ = tx.send(value);
         }
     }
 }
 
+/// One speaker's voice on a tick, scaled by their gain.
 #[derive(Debug)]
-struct AudioRx {
-    rx: broadcast::Receiver<Arc<[u8]>>,
-    buf: Arc<[u8]>,
-    cur: usize,
-    handle: runtime::Handle,
+pub struct Speech {
+    user: Option<UserId>,
+    /// laid out as [`PcmFormat::DISCORD_VOICE`](crate::pcm::PcmFormat::DISCORD_VOICE).
+    pcm: Vec<i32>,
+    /// the speaker's packet, kept at unity gain only.
+    opus: Option<Arc<[u8]>>,
 }
-impl AudioRx {
-    pub fn new(tx: &broadcast::Sender<Arc<[u8]>>) -> Self {
-        let rx = tx.subscribe();
-        Self {
-            rx,
-            buf: [].as_slice().into(),
-            cur: 0,
-            handle: runtime::Handle::current(),
+
+impl Speech {
+    /// Sums `speech` into one frame, `None` if there is none.
+    fn mix<'a>(speech: impl Iterator<Item = &'a Speech>) -> Option<Voice> {
+        let mut acc: Vec<i32> = Vec::new();
+        let mut count = 0;
+        let mut opus = None;
+        for s in speech {
+            count += 1;
+            opus = s.opus.clone();
+            if acc.len() < s.pcm.len() {
+                acc.resize(s.pcm.len(), 0);
+            }
+            for (a, x) in acc.iter_mut().zip(&s.pcm) {
+                *a += x;
+            }
         }
+        // a packet only stands for the tick if it is the only voice in it
+        (count > 0).then(|| Voice {
+            pcm: acc.into(),
+            opus: opus.filter(|_| count == 1),
+        })
     }
+}
 
-    pub fn new_input(tx: &broadcast::Sender<Arc<[u8]>>, _sample_rate: u32) -> Input {
-        let input = Box::new(Self::new(tx));
-        let mut hint = Hint::new();
-        hint.mime_type("audio/wav");
-        hint.with_extension("wav");
-
-        Input::Live(
-            LiveInput::Raw(AudioStream {
-                input,
-                hint: Some(hint),
-            }),
-            None,
-        )
+impl Drop for AudioTx {
+    fn drop(&mut self) {
+        if let Some((mixer, _)) = &self.output {
+            mixer.close();
+            self.tracks.dec();
+        }
     }
 }
 
-impl std::io::Read for AudioRx {
-    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        // if buf.len() % 2 == 1 {
-        //     dbg!(buf.len());
-        //     dbg!("invalide alignment");
-        //     return Err(Error::new(ErrorKind::InvalidData, "invalide alignment"));
-        // }
-        let handle = self.handle.clone();
-        let mut count = 0;
-        for dst in buf.iter_mut() {
-            if self.cur == self.buf.len() {
-                use broadcast::error::RecvError::*;
-                use broadcast::error::TryRecvError;
-                self.buf = loop {
-                    match self.rx.try_recv() {
-                        Ok(d) => break d,
-                        Err(TryRecvError::Closed) => return Ok(count),
-                        Err(TryRecvError::Lagged(x)) => eprintln!("lagged {x}"),
-                        Err(TryRecvError::Empty) => {
-                            break loop {
-                                match handle.block_on(self.rx.recv()) {
-                                    Ok(data) => break data,
-                                    Err(Closed) => {
-                                        eprintln!("channel closed");
-                                        return Ok(count);
-                                    }
-                                    Err(Lagged(x)) => eprintln!("lagged {x}"),
-                                }
-                            }
-                        }
-                    }
-                };
+#[cfg(test)]
+mod tests {
+    use tokio::time::{sleep, Duration};
+
+    use super::*;
+    use crate::pcm::PcmFormat;
+    use crate::pool::BotPool;
+    use crate::sim::{bot_user, Played, Sim, TempState};
+
+    const GUILD: GuildId = GuildId::new(1);
+    const PEER: GuildId = GuildId::new(2);
+    const A: ChannelId = ChannelId::new(10);
+    const B: ChannelId = ChannelId::new(11);
+    const C: ChannelId = ChannelId::new(12);
+    const ALICE: UserId = UserId(100);
+    const BOB: UserId = UserId(101);
 
-                if self.buf.is_empty() {
-                    eprintln!("recv empty buf");
-                    return Ok(count);
-                };
-                self.cur = 0;
+    /// The audio service with `bots` simulated bots, saving its state to a
+    /// file removed on drop.
+    struct Bridge {
+        sim: Sim,
+        handler: Arc<AudioServiceHandler>,
+        _state: TempState,
+    }
+
+    impl Bridge {
+        fn new(name: &str, bots: usize, passthrough: bool) -> Self {
+            let sim = Sim::new();
+            let state = TempState::new(name);
+            let bot_users: BotUsers = Default::default();
+            for bot in 0..bots {
+                bot_users.insert(bot_user(bot));
             }
-            *dst = self.buf[self.cur];
-            self.cur += 1;
-            count += 1;
+            let handler = AudioServiceHandler::new(
+                (0..bots).map(|bot| sim.driver(bot)).collect(),
+                Default::default(),
+                Default::default(),
+                bot_users,
+                state.open(),
+                Arc::new(BotPool::new(bots)),
+                passthrough,
+                std::env::temp_dir(),
+            );
+            Self {
+                sim,
+                handler: Arc::new(handler),
+                _state: state,
+            }
+        }
+
+        async fn send(&self, payload: AudioCommandPayload) -> Result<(), AudioCommandError> {
+            let (tx, rx) = oneshot::channel();
+            self.handler
+                .handle_command(AudioCommand { payload, tx })
+                .await;
+            rx.await.unwrap()
+        }
+
+        async fn link(&self, from_id: ChannelId, to_id: ChannelId, mode: LinkMode) {
+            let payload = AudioCommandPayload::Connect {
+                gid: GUILD,
+                from_id,
+                to_gid: GUILD,
+                to_id,
+                mode,
+            };
+            self.send(payload).await.unwrap();
+        }
+
+        /// Has `ssrc` say `level` in `cid` for `ticks` ticks, returning what
+        /// `dest` played meanwhile.
+        async fn speak(
+            &self,
+            cid: ChannelId,
+            ssrc: u32,
+            level: i16,
+            ticks: usize,
+            dest: ChannelId,
+        ) -> Vec<Played> {
+            self.sim.played(dest);
+            for _ in 0..ticks {
+                self.sim.say(cid, ssrc, frame(level), None);
+                self.sim.tick().await;
+            }
+            self.sim.played(dest)
         }
-        Ok(count)
     }
-}
-impl std::io::Seek for AudioRx {
-    fn seek(&mut self, _: std::io::SeekFrom) -> std::io::Result<u64> {
-        Err(std::io::Error::new(
-            std::io::ErrorKind::Unsupported,
-            "not seekable audio",
-        ))
+
+    fn frame(level: i16) -> Vec<i16> {
+        vec![level; PcmFormat::DISCORD_VOICE.frame_samples()]
     }
-}
 
-impl MediaSource for AudioRx {
-    fn is_seekable(&self) -> bool {
-        false
+    /// The level of the last frame played, `None` if nothing was.
+    fn last_level(played: &[Played]) -> Option<i16> {
+        match played.last()? {
+            Played::Pcm(pcm) => Some(pcm[0]),
+            Played::Opus(_) => panic!("expected PCM"),
+        }
+    }
+
+    #[tokio::test]
+    async fn join_takes_a_free_bot_per_channel() {
+        let bridge = Bridge::new("join", 2, false);
+        bridge
+            .send(AudioCommandPayload::Join(GUILD, A))
+            .await
+            .unwrap();
+        bridge
+            .send(AudioCommandPayload::Join(GUILD, A))
+            .await
+            .unwrap();
+        bridge
+            .send(AudioCommandPayload::Join(GUILD, B))
+            .await
+            .unwrap();
+        assert_eq!(bridge.sim.bot_in(A), Some(0));
+        assert_eq!(bridge.sim.bot_in(B), Some(1));
+        let res = bridge.send(AudioCommandPayload::Join(GUILD, C)).await;
+        assert!(matches!(res, Err(AudioCommandError::BotUsedFull)));
+
+        bridge
+            .send(AudioCommandPayload::Remove(GUILD, A))
+            .await
+            .unwrap();
+        assert_eq!(bridge.sim.bot_in(A), None);
+        let res = bridge.send(AudioCommandPayload::Remove(GUILD, A)).await;
+        assert!(matches!(res, Err(AudioCommandError::ChannelNotFound)));
+        bridge
+            .send(AudioCommandPayload::Join(GUILD, C))
+            .await
+            .unwrap();
+        assert_eq!(bridge.sim.bot_in(C), Some(0));
     }
-    fn byte_len(&self) -> Option<u64> {
-        None
+
+    #[tokio::test]
+    async fn link_forwards_voice_one_way() {
+        let bridge = Bridge::new("link", 2, false);
+        bridge
+            .send(AudioCommandPayload::Join(GUILD, A))
+            .await
+            .unwrap();
+        bridge
+            .send(AudioCommandPayload::Join(GUILD, B))
+            .await
+            .unwrap();
+        bridge.link(A, B, LinkMode::OneWay).await;
+        bridge.sim.enter(GUILD, A, ALICE, 1).await;
+        bridge.sim.enter(GUILD, B, BOB, 2).await;
+        assert_eq!(bridge.sim.tracks(A), 0);
+        assert_eq!(bridge.sim.tracks(B), 1);
+
+        let played = bridge.speak(A, 1, 1000, 10, B).await;
+        assert_eq!(played.len(), 10);
+        // the jitter buffer fills before anything is heard
+        assert_eq!(played.first(), Some(&Played::Pcm(frame(0))));
+        assert_eq!(last_level(&played), Some(1000));
+
+        let played = bridge.speak(B, 2, 1000, 10, A).await;
+        assert!(played.is_empty());
+    }
+
+    #[tokio::test]
+    async fn bidirectional_link_forwards_both_ways() {
+        let bridge = Bridge::new("bidi", 2, false);
+        bridge
+            .send(AudioCommandPayload::Join(GUILD, A))
+            .await
+            .unwrap();
+        bridge
+            .send(AudioCommandPayload::Join(GUILD, B))
+            .await
+            .unwrap();
+        bridge.link(A, B, LinkMode::Bidirectional).await;
+        bridge.sim.enter(GUILD, A, ALICE, 1).await;
+        bridge.sim.enter(GUILD, B, BOB, 2).await;
+
+        let played = bridge.speak(A, 1, 1000, 10, B).await;
+        assert_eq!(last_level(&played), Some(1000));
+        let played = bridge.speak(B, 2, -500, 10, A).await;
+        assert_eq!(last_level(&played), Some(-500));
+    }
+
+    #[tokio::test]
+    async fn unlink_stops_the_track() {
+        let bridge = Bridge::new("unlink", 2, false);
+        bridge
+            .send(AudioCommandPayload::Join(GUILD, A))
+            .await
+            .unwrap();
+        bridge
+            .send(AudioCommandPayload::Join(GUILD, B))
+            .await
+            .unwrap();
+        bridge.link(A, B, LinkMode::OneWay).await;
+        bridge.sim.enter(GUILD, A, ALICE, 1).await;
+        bridge.speak(A, 1, 1000, 10, B).await;
+
+        let payload = AudioCommandPayload::Disconnect {
+            gid: GUILD,
+            from_id: A,
+            to_id: B,
+        };
+        bridge.send(payload).await.unwrap();
+        let played = bridge.speak(A, 1, 1000, 10, B).await;
+        assert!(played.is_empty());
+        assert_eq!(bridge.sim.tracks(B), 0);
+
+        let payload = AudioCommandPayload::Disconnect {
+            gid: GUILD,
+            from_id: A,
+            to_id: B,
+        };
+        let res = bridge.send(payload).await;
+        assert!(matches!(res, Err(AudioCommandError::LinkNotFound)));
+    }
+
+    #[tokio::test]
+    async fn leaving_unroutes_the_channel() {
+        let bridge = Bridge::new("leave", 3, false);
+        for cid in [A, B, C] {
+            bridge
+                .send(AudioCommandPayload::Join(GUILD, cid))
+                .await
+                .unwrap();
+        }
+        bridge.link(A, B, LinkMode::OneWay).await;
+        bridge.link(C, B, LinkMode::OneWay).await;
+        bridge.sim.enter(GUILD, A, ALICE, 1).await;
+        bridge.sim.enter(GUILD, C, BOB, 2).await;
+
+        bridge
+            .send(AudioCommandPayload::Remove(GUILD, C))
+            .await
+            .unwrap();
+        assert_eq!(bridge.handler.routes.lock().await.links(GUILD).len(), 1);
+        let played = bridge.speak(A, 1, 1000, 10, B).await;
+        assert_eq!(last_level(&played), Some(1000));
+    }
+
+    /// Bots in A and B, linked one way, before A's voice connection fails.
+    async fn lose_a(name: &str, failing_joins: usize) -> Bridge {
+        let bridge = Bridge::new(name, 2, false);
+        for cid in [A, B] {
+            bridge
+                .send(AudioCommandPayload::Join(GUILD, cid))
+                .await
+                .unwrap();
+        }
+        bridge.link(A, B, LinkMode::OneWay).await;
+        bridge.sim.fail_joins(failing_joins);
+        bridge.sim.disconnect(A).await;
+        bridge
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_failed_call_is_rejoined_with_its_links() {
+        let bridge = lose_a("rejoin", 2).await;
+        // attempts after 0.5 s, 1.5 s, then 3.5 s
+        sleep(Duration::from_secs(3)).await;
+        assert_eq!(bridge.sim.bot_in(A), None);
+        assert!(bridge.handler.routes.lock().await.links(GUILD).is_empty());
+
+        sleep(Duration::from_secs(1)).await;
+        assert_eq!(bridge.sim.bot_in(A), Some(0));
+        let links = bridge.handler.routes.lock().await.links(GUILD).to_vec();
+        assert_eq!(links, [Link::new(A, B, LinkMode::OneWay)]);
+        assert!(bridge.handler.recovering.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rejoining_gives_up_after_ten_attempts() {
+        let bridge = lose_a("give-up", 11).await;
+        // the tenth attempt comes after 151.5 s
+        sleep(Duration::from_secs(150)).await;
+        assert!(bridge.handler.recovering.contains(&(GUILD, A)));
+
+        sleep(Duration::from_secs(2)).await;
+        assert!(bridge.handler.recovering.is_empty());
+        sleep(Duration::from_secs(60)).await;
+        assert_eq!(bridge.sim.bot_in(A), None);
+
+        // ten attempts used ten of the eleven failures
+        let res = bridge.send(AudioCommandPayload::Join(GUILD, A)).await;
+        assert!(matches!(res, Err(AudioCommandError::UnknownError)));
+        bridge
+            .send(AudioCommandPayload::Join(GUILD, A))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn volume_scales_a_user() {
+        let bridge = Bridge::new("volume", 2, false);
+        bridge
+            .send(AudioCommandPayload::Join(GUILD, A))
+            .await
+            .unwrap();
+        bridge
+            .send(AudioCommandPayload::Join(GUILD, B))
+            .await
+            .unwrap();
+        bridge.link(A, B, LinkMode::OneWay).await;
+        bridge.sim.enter(GUILD, A, ALICE, 1).await;
+        bridge.sim.enter(GUILD, A, BOB, 2).await;
+        bridge
+            .handler
+            .volume_map
+            .entry(GUILD)
+            .or_default()
+            .insert(ALICE, Gain::from_db(-20.0));
+
+        let played = bridge.speak(A, 1, 1000, 10, B).await;
+        assert_eq!(last_level(&played), Some(100));
+        let played = bridge.speak(A, 2, 1000, 10, B).await;
+        assert_eq!(last_level(&played), Some(1000));
+
+        // both at once are summed
+        for _ in 0..10 {
+            bridge.sim.say(A, 1, frame(1000), None);
+            bridge.sim.say(A, 2, frame(1000), None);
+            bridge.sim.tick().await;
+        }
+        assert_eq!(last_level(&bridge.sim.played(B)), Some(1100));
+    }
+
+    #[tokio::test]
+    async fn speaker_filters_pick_who_is_forwarded() {
+        let bridge = Bridge::new("speakers", 3, false);
+        for cid in [A, B, C] {
+            bridge
+                .send(AudioCommandPayload::Join(GUILD, cid))
+                .await
+                .unwrap();
+        }
+        bridge.link(A, B, LinkMode::OneWay).await;
+        bridge.link(A, C, LinkMode::OneWay).await;
+        bridge.sim.enter(GUILD, A, ALICE, 1).await;
+        bridge.sim.enter(GUILD, A, BOB, 2).await;
+        let filter =
+            |allow: Vec<UserId>, deny: Vec<UserId>, to_id| AudioCommandPayload::SetSpeakers {
+                gid: GUILD,
+                from_id: A,
+                to_id,
+                filter: SpeakerFilter { allow, deny },
+            };
+        bridge.send(filter(vec![ALICE], vec![], B)).await.unwrap();
+        bridge.send(filter(vec![], vec![ALICE], C)).await.unwrap();
+
+        for _ in 0..10 {
+            bridge.sim.say(A, 1, frame(1000), None);
+            bridge.sim.say(A, 2, frame(300), None);
+            // nobody is known to speak as 3 yet
+            bridge.sim.say(A, 3, frame(50), None);
+            bridge.sim.tick().await;
+        }
+        assert_eq!(last_level(&bridge.sim.played(B)), Some(1000));
+        assert_eq!(last_level(&bridge.sim.played(C)), Some(300));
+
+        // unlinking forgets the filter, like the link's jitter buffer
+        let payload = AudioCommandPayload::Disconnect {
+            gid: GUILD,
+            from_id: A,
+            to_id: C,
+        };
+        bridge.send(payload).await.unwrap();
+        let res = bridge.send(filter(vec![], vec![ALICE], C)).await;
+        assert!(matches!(res, Err(AudioCommandError::LinkNotFound)));
+        bridge.link(A, C, LinkMode::OneWay).await;
+        let played = bridge.speak(A, 1, 1000, 10, C).await;
+        assert_eq!(last_level(&played), Some(1000));
+    }
+
+    #[tokio::test]
+    async fn broadcast_fans_out_one_way() {
+        let bridge = Bridge::new("broadcast", 3, false);
+        let name = || "town hall".to_string();
+        let payload = AudioCommandPayload::StartBroadcast {
+            gid: GUILD,
+            name: name(),
+            source: A,
+        };
+        bridge.send(payload).await.unwrap();
+        for cid in [B, C] {
+            let payload = AudioCommandPayload::AddListener {
+                gid: GUILD,
+                name: name(),
+                cid,
+            };
+            bridge.send(payload).await.unwrap();
+        }
+        // listeners get a bot of their own
+        assert_eq!(bridge.sim.bot_in(B), Some(1));
+        assert_eq!(bridge.sim.bot_in(C), Some(2));
+        bridge.sim.enter(GUILD, A, ALICE, 1).await;
+        bridge.sim.enter(GUILD, B, BOB, 2).await;
+        for _ in 0..10 {
+            bridge.sim.say(A, 1, frame(1000), None);
+            bridge.sim.tick().await;
+        }
+        assert_eq!(last_level(&bridge.sim.played(B)), Some(1000));
+        assert_eq!(last_level(&bridge.sim.played(C)), Some(1000));
+
+        // nothing gets back out of a listener
+        for mode in [LinkMode::OneWay, LinkMode::Bidirectional] {
+            let payload = AudioCommandPayload::Connect {
+                gid: GUILD,
+                from_id: B,
+                to_gid: GUILD,
+                to_id: A,
+                mode,
+            };
+            let res = bridge.send(payload).await;
+            assert!(matches!(
+                res,
+                Err(AudioCommandError::Routing(RoutingError::ReceiveOnly))
+            ));
+        }
+        let payload = AudioCommandPayload::Disconnect {
+            gid: GUILD,
+            from_id: A,
+            to_id: B,
+        };
+        let res = bridge.send(payload).await;
+        assert!(matches!(
+            res,
+            Err(AudioCommandError::Routing(RoutingError::Broadcast))
+        ));
+
+        let payload = AudioCommandPayload::RemoveListener {
+            gid: GUILD,
+            name: name(),
+            cid: C,
+        };
+        bridge.send(payload).await.unwrap();
+        assert_eq!(bridge.sim.bot_in(C), None);
+        let payload = AudioCommandPayload::EndBroadcast {
+            gid: GUILD,
+            name: name(),
+        };
+        bridge.send(payload).await.unwrap();
+        assert_eq!(bridge.sim.bot_in(B), None);
+        assert_eq!(bridge.sim.bot_in(A), None);
+        let state = bridge.handler.storage.state().await;
+        assert!(state.broadcasts.is_empty());
+        assert!(state.joins.is_empty());
+    }
+
+    #[tokio::test]
+    async fn leaving_the_source_releases_the_listeners() {
+        let bridge = Bridge::new("broadcast-leave", 3, false);
+        let payload = AudioCommandPayload::StartBroadcast {
+            gid: GUILD,
+            name: "stage".to_string(),
+            source: A,
+        };
+        bridge.send(payload).await.unwrap();
+        for cid in [B, C] {
+            let payload = AudioCommandPayload::AddListener {
+                gid: GUILD,
+                name: "stage".to_string(),
+                cid,
+            };
+            bridge.send(payload).await.unwrap();
+        }
+        bridge
+            .send(AudioCommandPayload::Remove(GUILD, A))
+            .await
+            .unwrap();
+        for cid in [A, B, C] {
+            assert_eq!(bridge.sim.bot_in(cid), None);
+        }
+        let state = bridge.handler.storage.state().await;
+        assert!(state.broadcasts.is_empty());
+        assert!(state.joins.is_empty());
+    }
+
+    #[tokio::test]
+    async fn recordings_stay_in_their_guild_and_directory() {
+        let bridge = Bridge::new("record", 1, false);
+        bridge
+            .send(AudioCommandPayload::Join(GUILD, A))
+            .await
+            .unwrap();
+        let mut dirs = Vec::new();
+        let options = RecordOptions {
+            layout: crate::record::Layout::Mixed,
+            format: crate::record::Format::Wav,
+        };
+        // only the channel's own guild may record it
+        let payload = AudioCommandPayload::StartRecording {
+            gid: PEER,
+            cid: A,
+            options,
+        };
+        let res = bridge.send(payload).await;
+        assert!(matches!(res, Err(AudioCommandError::ChannelNotFound)));
+        for _ in 0..2 {
+            let payload = AudioCommandPayload::StartRecording {
+                gid: GUILD,
+                cid: A,
+                options,
+            };
+            bridge.send(payload).await.unwrap();
+            let (reply, _) = oneshot::channel();
+            let payload = AudioCommandPayload::StopRecording {
+                gid: PEER,
+                cid: A,
+                reply,
+            };
+            let res = bridge.send(payload).await;
+            assert!(matches!(res, Err(AudioCommandError::NotRecording)));
+            let (reply, rx) = oneshot::channel();
+            let payload = AudioCommandPayload::StopRecording {
+                gid: GUILD,
+                cid: A,
+                reply,
+            };
+            bridge.send(payload).await.unwrap();
+            dirs.push(rx.await.unwrap());
+        }
+        for dir in &dirs {
+            let _ = std::fs::remove_dir_all(dir);
+        }
+        assert_ne!(dirs[0], dirs[1]);
+    }
+
+    #[tokio::test]
+    async fn bot_voices_are_not_forwarded() {
+        let bridge = Bridge::new("bots", 2, false);
+        bridge
+            .send(AudioCommandPayload::Join(GUILD, A))
+            .await
+            .unwrap();
+        bridge
+            .send(AudioCommandPayload::Join(GUILD, B))
+            .await
+            .unwrap();
+        bridge.link(A, B, LinkMode::OneWay).await;
+        bridge.sim.enter(GUILD, A, bot_user(1), 7).await;
+
+        let played = bridge.speak(A, 7, 1000, 10, B).await;
+        assert_eq!(last_level(&played), Some(0));
+    }
+
+    #[tokio::test]
+    async fn passthrough_plays_the_speakers_packets() {
+        let bridge = Bridge::new("passthrough", 2, true);
+        bridge
+            .send(AudioCommandPayload::Join(GUILD, A))
+            .await
+            .unwrap();
+        bridge
+            .send(AudioCommandPayload::Join(GUILD, B))
+            .await
+            .unwrap();
+        bridge.link(A, B, LinkMode::OneWay).await;
+        bridge.sim.enter(GUILD, A, ALICE, 1).await;
+
+        let packet = [0xfc, 1, 2, 3];
+        for _ in 0..10 {
+            bridge.sim.say(A, 1, frame(1000), Some(&packet));
+            bridge.sim.tick().await;
+        }
+        let played = bridge.sim.played(B);
+        assert_eq!(played.last(), Some(&Played::Opus(packet.to_vec())));
+    }
+
+    #[tokio::test]
+    async fn links_across_guilds_need_both_to_agree() {
+        let bridge = Bridge::new("peers", 3, false);
+        bridge
+            .send(AudioCommandPayload::Join(GUILD, A))
+            .await
+            .unwrap();
+        bridge
+            .send(AudioCommandPayload::Join(PEER, B))
+            .await
+            .unwrap();
+        let connect = |to_gid, to_id| AudioCommandPayload::Connect {
+            gid: GUILD,
+            from_id: A,
+            to_gid,
+            to_id,
+            mode: LinkMode::OneWay,
+        };
+        let res = bridge.send(connect(PEER, B)).await;
+        assert!(matches!(res, Err(AudioCommandError::NotPeered)));
+
+        let storage = &bridge.handler.storage;
+        storage.update(|s| s.add_peer(GUILD, PEER)).await;
+        storage.update(|s| s.add_peer(PEER, GUILD)).await;
+        // a channel of a third guild can't be passed off as the peer's
+        bridge
+            .send(AudioCommandPayload::Join(GuildId::new(3), C))
+            .await
+            .unwrap();
+        let res = bridge.send(connect(PEER, C)).await;
+        assert!(matches!(res, Err(AudioCommandError::ChannelNotFound)));
+
+        bridge.send(connect(PEER, B)).await.unwrap();
+        bridge.sim.enter(GUILD, A, ALICE, 1).await;
+        let played = bridge.speak(A, 1, 1000, 10, B).await;
+        assert_eq!(last_level(&played), Some(1000));
     }
 }
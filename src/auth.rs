@@ -0,0 +1,143 @@
+use poise::serenity_prelude::{Member, Permissions, RoleId};
+
+use crate::types::Ctx;
+
+/// Something a bridge command does that not every member may do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// bring a bot into a channel.
+    Join,
+    /// create, retune or remove links.
+    Link,
+    /// change the volume of someone else.
+    Volume,
+    /// make a bot leave, tearing its links down.
+    Leave,
+    /// start or stop recording a channel.
+    Record,
+    /// change who may do the above.
+    Configure,
+}
+
+impl Action {
+    /// The Discord permission that allows this on its own.
+    fn permission(self) -> Permissions {
+        match self {
+            Action::Join | Action::Link | Action::Leave => Permissions::MOVE_MEMBERS,
+            Action::Volume => Permissions::MUTE_MEMBERS,
+            Action::Record => Permissions::MANAGE_CHANNELS,
+            Action::Configure => Permissions::MANAGE_GUILD,
+        }
+    }
+
+    fn describe(self) -> &'static str {
+        match self {
+            Action::Join => "bring bots into channels",
+            Action::Link => "change links",
+            Action::Volume => "change other members' volume",
+            Action::Leave => "remove bots from channels",
+            Action::Record => "record channels",
+            Action::Configure => "configure bridge roles",
+        }
+    }
+}
+
+/// Whether `member` may do `action`, given the guild's bridge `roles`.
+///
+/// Administrators may do anything. Otherwise the member needs the action's
+/// permission, or one of the bridge roles for anything but configuring them.
+pub fn allowed(member: &Member, roles: &[RoleId], action: Action) -> bool {
+    // only filled in for interactions, which is all we handle
+    let perms = member.permissions.unwrap_or_default();
+    if perms.administrator() || perms.contains(action.permission()) {
+        return true;
+    }
+    action != Action::Configure && member.roles.iter().any(|r| roles.contains(r))
+}
+
+/// Checks `action` for the author, replying with an ephemeral denial if not
+/// allowed.
+pub async fn authorize(ctx: Ctx<'_>, action: Action) -> anyhow::Result<bool> {
+    let Some(gid) = ctx.guild_id() else {
+        return Ok(false);
+    };
+    let roles = ctx.data().storage.roles(gid).await;
+    let ok = match ctx.author_member().await {
+        Some(member) => allowed(&member, &roles, action),
+        None => false,
+    };
+    if !ok {
+        tracing::info!(user = ctx.author().id.get(), ?action, "denied");
+        let roles = if action == Action::Configure {
+            ""
+        } else {
+            " or a bridge role"
+        };
+        let msg = format!(
+            "You may not {}, that needs the {} permission{}.",
+            action.describe(),
+            action.permission().get_permission_names().join(", "),
+            roles,
+        );
+        ctx.send(poise::CreateReply::default().content(msg).ephemeral(true))
+            .await?;
+    }
+    Ok(ok)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BRIDGE: RoleId = RoleId::new(1);
+    const OTHER: RoleId = RoleId::new(2);
+
+    fn member(perms: Permissions, roles: &[RoleId]) -> Member {
+        let mut member = Member::default();
+        member.permissions = Some(perms);
+        member.roles = roles.to_vec();
+        member
+    }
+
+    #[test]
+    fn each_action_needs_its_permission_or_a_bridge_role() {
+        let table = [
+            (Action::Join, Permissions::MOVE_MEMBERS),
+            (Action::Link, Permissions::MOVE_MEMBERS),
+            (Action::Leave, Permissions::MOVE_MEMBERS),
+            (Action::Volume, Permissions::MUTE_MEMBERS),
+            (Action::Record, Permissions::MANAGE_CHANNELS),
+            (Action::Configure, Permissions::MANAGE_GUILD),
+        ];
+        let roles = [BRIDGE];
+        for (action, perm) in table {
+            let others = Permissions::all() - perm - Permissions::ADMINISTRATOR;
+            assert!(allowed(&member(perm, &[]), &roles, action), "{:?}", action);
+            assert!(
+                !allowed(&member(others, &[OTHER]), &roles, action),
+                "{:?}",
+                action
+            );
+            assert!(
+                allowed(&member(Permissions::ADMINISTRATOR, &[]), &roles, action),
+                "{:?}",
+                action
+            );
+            // a bridge role stands in for anything but configuring the roles
+            let with_role = allowed(&member(Permissions::empty(), &[BRIDGE]), &roles, action);
+            assert_eq!(with_role, action != Action::Configure, "{:?}", action);
+            assert!(!allowed(
+                &member(Permissions::empty(), &[BRIDGE]),
+                &[],
+                action
+            ));
+        }
+    }
+
+    #[test]
+    fn permissions_default_to_none() {
+        let mut member = Member::default();
+        member.roles = vec![OTHER];
+        assert!(!allowed(&member, &[BRIDGE], Action::Join));
+    }
+}
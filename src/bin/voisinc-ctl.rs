@@ -0,0 +1,304 @@
+//! Inspects and changes a running voisinc through its control socket.
+//!
+//! Speaks the control API (see `src/api.rs`) over the Unix socket set as
+//! `listen.socket`, printing tables or, with `--json`, the API's JSON.
+
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+
+use anyhow::{bail, Context};
+use serde_json::{json, Value};
+
+const USAGE: &str = "\
+Usage: voisinc-ctl [--socket PATH] [--json] COMMAND
+
+Commands:
+  bots                          bots of the pool and their calls
+  calls [GUILD]                 calls with their links and speakers
+  links GUILD                   links of a guild
+  broadcasts GUILD              broadcasts of a guild and their listeners
+  leave GUILD                   leave every channel of a guild
+  link GUILD FROM TO [--both] [--to-guild GUILD]
+                                play FROM in TO, or both ways
+  unlink GUILD FROM TO          stop playing FROM in TO
+
+The socket is the server's listen.socket; it defaults to $VOISINC_SOCKET,
+which also sets it on the server side.";
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("voisinc-ctl: {:#}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run() -> anyhow::Result<()> {
+    let mut socket = std::env::var_os("VOISINC_SOCKET").map(PathBuf::from);
+    let mut json = false;
+    let mut both = false;
+    let mut to_guild = None;
+    let mut args = Vec::new();
+    let mut argv = std::env::args().skip(1);
+    while let Some(arg) = argv.next() {
+        match arg.as_str() {
+            "--socket" => socket = Some(argv.next().context("--socket needs a path")?.into()),
+            "--json" => json = true,
+            "--both" => both = true,
+            "--to-guild" => to_guild = Some(argv.next().context("--to-guild needs a guild")?),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return Ok(());
+            }
+            _ if arg.starts_with("--") => bail!("unknown option {}\n\n{}", arg, USAGE),
+            _ => args.push(arg),
+        }
+    }
+    let socket = socket.context("no socket given, pass --socket or set $VOISINC_SOCKET")?;
+    let client = Client { socket };
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args[..] {
+        ["bots"] => {
+            let bots = client.request("GET", "/v1/bots", None)?;
+            if json {
+                return print_json(&bots);
+            }
+            let rows = items(&bots)
+                .map(|b| {
+                    let calls = items(&b["calls"])
+                        .map(|c| format!("{}/{}", text(&c["guild"]), text(&c["channel"])))
+                        .collect::<Vec<_>>();
+                    vec![
+                        text(&b["index"]),
+                        yes_no(&b["healthy"]),
+                        or_dash(calls.join(", ")),
+                    ]
+                })
+                .collect();
+            print_table(&["BOT", "HEALTHY", "CALLS"], rows);
+        }
+        ["calls"] => calls(&client, None, json)?,
+        ["calls", guild] => calls(&client, Some(guild), json)?,
+        ["links", guild] => {
+            let topology = client.request("GET", &format!("/v1/guilds/{}", guild), None)?;
+            if json {
+                return print_json(&topology["links"]);
+            }
+            let rows = items(&topology["links"])
+                .map(|l| vec![text(&l["from_id"]), text(&l["to_id"]), text(&l["mode"])])
+                .collect();
+            print_table(&["FROM", "TO", "MODE"], rows);
+        }
+        ["broadcasts", guild] => {
+            let topology = client.request("GET", &format!("/v1/guilds/{}", guild), None)?;
+            if json {
+                return print_json(&topology["broadcasts"]);
+            }
+            let rows = items(&topology["broadcasts"])
+                .map(|b| {
+                    let listeners = items(&b["listeners"]).map(text).collect::<Vec<_>>();
+                    vec![
+                        text(&b["name"]),
+                        text(&b["source"]),
+                        or_dash(listeners.join(", ")),
+                    ]
+                })
+                .collect();
+            print_table(&["NAME", "SOURCE", "LISTENERS"], rows);
+        }
+        ["leave", guild] => {
+            client.request("DELETE", &format!("/v1/guilds/{}", guild), None)?;
+            done(json, format!("left every channel of guild {}", guild));
+        }
+        ["link", guild, from, to] => {
+            let mut body = json!({ "from": from, "to": to, "bidirectional": both });
+            if let Some(to_guild) = &to_guild {
+                body["to_guild"] = json!(to_guild);
+            }
+            let path = format!("/v1/guilds/{}/links", guild);
+            client.request("POST", &path, Some(&body))?;
+            let arrow = if both { "<->" } else { "->" };
+            done(json, format!("linked {} {} {}", from, arrow, to));
+        }
+        ["unlink", guild, from, to] => {
+            let path = format!("/v1/guilds/{}/links/{}/{}", guild, from, to);
+            client.request("DELETE", &path, None)?;
+            done(json, format!("unlinked {} -> {}", from, to));
+        }
+        _ => bail!("{}", USAGE),
+    }
+    Ok(())
+}
+
+fn calls(client: &Client, guild: Option<&str>, json: bool) -> anyhow::Result<()> {
+    let calls = client.request("GET", "/v1/calls", None)?;
+    let calls: Vec<&Value> = items(&calls)
+        .filter(|c| guild.is_none_or(|g| text(&c["guild"]) == g))
+        .collect();
+    if json {
+        return print_json(&json!(calls));
+    }
+    let list = |v: &Value| or_dash(items(v).map(text).collect::<Vec<_>>().join(", "));
+    let rows = calls
+        .iter()
+        .map(|c| {
+            let recording = match &c["recording"] {
+                Value::Null => "-".to_string(),
+                r => format!("{} {}", text(&r["layout"]), text(&r["format"])),
+            };
+            vec![
+                text(&c["guild"]),
+                text(&c["index"]),
+                text(&c["channel"]),
+                list(&c["inbound"]),
+                list(&c["outbound"]),
+                list(&c["speakers"]),
+                yes_no(&c["opus"]),
+                recording,
+            ]
+        })
+        .collect();
+    let header = [
+        "GUILD",
+        "BOT",
+        "CHANNEL",
+        "HEARS",
+        "HEARD IN",
+        "SPEAKERS",
+        "OPUS",
+        "RECORDING",
+    ];
+    print_table(&header, rows);
+    Ok(())
+}
+
+/// The running instance's control API.
+struct Client {
+    socket: PathBuf,
+}
+
+impl Client {
+    /// Sends one request, giving the JSON answered or the API's error.
+    fn request(&self, method: &str, path: &str, body: Option<&Value>) -> anyhow::Result<Value> {
+        let mut stream = UnixStream::connect(&self.socket)
+            .with_context(|| format!("failed to connect to {}", self.socket.display()))?;
+        let body = body.map(Value::to_string).unwrap_or_default();
+        write!(
+            stream,
+            "{} {} HTTP/1.1\r\nHost: voisinc\r\nConnection: close\r\n\
+             Content-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            method,
+            path,
+            body.len(),
+            body
+        )?;
+        let mut res = Vec::new();
+        stream.read_to_end(&mut res)?;
+        let res = String::from_utf8(res).context("response is not UTF-8")?;
+        let (head, body) = res.split_once("\r\n\r\n").context("malformed response")?;
+        let status: u16 = head
+            .get(9..12)
+            .and_then(|s| s.parse().ok())
+            .context("malformed response")?;
+        let chunked = head
+            .lines()
+            .any(|l| l.eq_ignore_ascii_case("transfer-encoding: chunked"));
+        let body = if chunked {
+            dechunk(body)?
+        } else {
+            body.to_string()
+        };
+        let value = match body.trim() {
+            "" => Value::Null,
+            text => serde_json::from_str(text).unwrap_or_else(|_| Value::String(text.into())),
+        };
+        if status >= 400 {
+            let message = value["message"].as_str().or(value.as_str()).unwrap_or("");
+            bail!("{} ({})", message, status);
+        }
+        Ok(value)
+    }
+}
+
+/// Joins the chunks of a `Transfer-Encoding: chunked` body.
+fn dechunk(mut body: &str) -> anyhow::Result<String> {
+    let mut out = String::new();
+    loop {
+        let (size, rest) = body.split_once("\r\n").context("malformed chunk")?;
+        let size = usize::from_str_radix(size.trim(), 16).context("malformed chunk")?;
+        if size == 0 {
+            return Ok(out);
+        }
+        out.push_str(rest.get(..size).context("truncated chunk")?);
+        body = rest.get(size + 2..).context("truncated chunk")?;
+    }
+}
+
+fn items(v: &Value) -> impl Iterator<Item = &Value> {
+    v.as_array().into_iter().flatten()
+}
+
+/// A JSON value as a table cell.
+fn text(v: &Value) -> String {
+    match v {
+        Value::String(s) => s.clone(),
+        Value::Null => "-".into(),
+        v => v.to_string(),
+    }
+}
+
+fn yes_no(v: &Value) -> String {
+    if v.as_bool() == Some(true) {
+        "yes"
+    } else {
+        "no"
+    }
+    .into()
+}
+
+fn or_dash(s: String) -> String {
+    if s.is_empty() {
+        "-".into()
+    } else {
+        s
+    }
+}
+
+fn print_json(v: &Value) -> anyhow::Result<()> {
+    println!("{}", serde_json::to_string_pretty(v)?);
+    Ok(())
+}
+
+/// Reports a change; JSON output gets an object to parse rather than prose.
+fn done(json: bool, message: String) {
+    if json {
+        println!("{}", json!({ "ok": true }));
+    } else {
+        println!("{}", message);
+    }
+}
+
+fn print_table(header: &[&str], rows: Vec<Vec<String>>) {
+    let mut widths: Vec<usize> = header.iter().map(|h| h.len()).collect();
+    for row in &rows {
+        for (w, cell) in widths.iter_mut().zip(row) {
+            *w = (*w).max(cell.chars().count());
+        }
+    }
+    let line = |cells: Vec<&str>| {
+        let last = cells.len() - 1;
+        let mut out = String::new();
+        for (i, (cell, w)) in cells.iter().zip(&widths).enumerate() {
+            if i == last {
+                out.push_str(cell);
+            } else {
+                out.push_str(&format!("{:<width$}  ", cell, width = w));
+            }
+        }
+        println!("{}", out.trim_end());
+    };
+    line(header.to_vec());
+    for row in &rows {
+        line(row.iter().map(String::as_str).collect());
+    }
+}
@@ -1,56 +1,1104 @@
 use crate::{
-    audio::{AudioCommandError, AudioCommandPayload},
+    audio::{AudioCommandError, AudioCommandPayload, Gain},
+    auth::{authorize, Action},
+    jitter::JitterConfig,
+    record::{Format, Layout, RecordOptions},
+    routing::{LinkMode, RoutingError, SpeakerFilter},
     types::Ctx,
 };
 use poise::serenity_prelude::*;
+use std::sync::Arc;
 use tracing::info;
 
 type Result = anyhow::Result<()>;
 
-#[poise::command(slash_command)]
+/// Turns an [`AudioCommandError`] into the text shown to the user.
+fn error_message(e: AudioCommandError) -> &'static str {
+    match e {
+        AudioCommandError::AudioTxNotFound => "source channel has no active bot",
+        AudioCommandError::ChannelNotFound => "no bot is in that channel, use /join first",
+        AudioCommandError::LinkNotFound => "those channels are not linked",
+        AudioCommandError::BroadcastNotFound => "there is no broadcast of that name",
+        AudioCommandError::Routing(RoutingError::SelfLink) => "can't link a channel to itself",
+        AudioCommandError::Routing(RoutingError::Loop) => {
+            "that link would make a loop, use bidirectional for two-way calls"
+        }
+        AudioCommandError::Routing(RoutingError::ReceiveOnly) => {
+            "that channel listens to a broadcast, its voice can't be played anywhere"
+        }
+        AudioCommandError::Routing(RoutingError::Sends) => {
+            "that channel's voice is played elsewhere, unlink it first"
+        }
+        AudioCommandError::Routing(RoutingError::Broadcast) => {
+            "that link is part of a broadcast, use /broadcast remove"
+        }
+        AudioCommandError::Routing(RoutingError::BroadcastExists) => {
+            "there already is a broadcast of that name"
+        }
+        AudioCommandError::BotUsedFull => {
+            "every bot already is in a channel of this server, /leave one first"
+        }
+        AudioCommandError::NotPeered => "both servers have to allow the link with /peer allow",
+        AudioCommandError::AlreadyRecording => "that channel already is being recorded",
+        AudioCommandError::NotRecording => "that channel is not being recorded",
+        AudioCommandError::RecordFailed => "recording failed, see the logs",
+        AudioCommandError::ProviderDropped => {
+            tracing::error!("AudioService is doropped");
+            "internal error"
+        }
+        AudioCommandError::UnknownError => "unknown error",
+    }
+}
+
+/// Resolves the channel argument, falling back to the author's voice channel.
+fn target_channel(
+    ctx: Ctx<'_>,
+    channel: Option<ChannelId>,
+) -> std::result::Result<(GuildId, ChannelId), &'static str> {
+    let guild = ctx.guild().ok_or("not in guild")?;
+    let vc = match channel {
+        Some(vc) => vc,
+        None => guild
+            .voice_states
+            .get(&ctx.author().id)
+            .and_then(|vs| vs.channel_id)
+            .ok_or("not in voice channel")?,
+    };
+    Ok((guild.id, vc))
+}
+
+async fn reply(ctx: Ctx<'_>, res: std::result::Result<String, &'static str>) {
+    let res = match res {
+        Err(e) => ctx.say(e).await,
+        Ok(msg) => ctx.say(msg).await,
+    };
+    if let Err(e) = res {
+        tracing::warn!("Failed to send message: {}", e);
+    }
+}
+
+#[poise::command(slash_command, guild_only)]
 #[tracing::instrument(name = "join", skip(ctx))]
-pub async fn join(ctx: Ctx<'_>) -> Result {
-    // let id = ctx.channel_id();
-    // let channel = ctx.guild().unwrap().channels.get(&id).unwrap().kind.clone();
-    let res = (async {
-        let vc;
-        let guild_id;
-        {
-            let guild = ctx.guild().ok_or("not in guild")?;
-            guild_id = guild.id;
-            vc = guild
-                .voice_states
-                .get(&ctx.author().id)
-                .and_then(|vs| vs.channel_id)
-                .ok_or("not in voice channel")?;
-        };
-        match ctx
-            .framework()
-            .user_data
+pub async fn join(
+    ctx: Ctx<'_>,
+    #[description = "Voice channel to join (defaults to yours)"]
+    #[channel_types("Voice", "Stage")]
+    channel: Option<ChannelId>,
+) -> Result {
+    if !authorize(ctx, Action::Join).await? {
+        return Ok(());
+    }
+    let res = (async {
+        let (guild_id, vc) = target_channel(ctx, channel)?;
+        ctx.data()
             .command(AudioCommandPayload::Join(guild_id, vc))
             .await
-        {
-            Err(AudioCommandError::ProviderDropped) => {
-                tracing::error!("AudioService is doropped");
-                return Err("internal error");
+            .map_err(error_message)?;
+        Ok(format!("Joined to <#{}>", vc))
+    })
+    .await;
+    reply(ctx, res).await;
+    Ok(())
+}
+
+#[poise::command(slash_command, guild_only)]
+#[tracing::instrument(name = "leave", skip(ctx))]
+pub async fn leave(
+    ctx: Ctx<'_>,
+    #[description = "Voice channel to leave (defaults to yours)"]
+    #[channel_types("Voice", "Stage")]
+    channel: Option<ChannelId>,
+) -> Result {
+    if !authorize(ctx, Action::Leave).await? {
+        return Ok(());
+    }
+    let res = (async {
+        let (guild_id, vc) = target_channel(ctx, channel)?;
+        ctx.data()
+            .command(AudioCommandPayload::Remove(guild_id, vc))
+            .await
+            .map_err(error_message)?;
+        Ok(format!("Left <#{}>", vc))
+    })
+    .await;
+    reply(ctx, res).await;
+    Ok(())
+}
+
+#[poise::command(slash_command, guild_only)]
+#[tracing::instrument(name = "link", skip(ctx))]
+pub async fn link(
+    ctx: Ctx<'_>,
+    #[description = "Channel to take the voice from"]
+    #[channel_types("Voice", "Stage")]
+    from: ChannelId,
+    #[description = "Channel to play the voice in"]
+    #[channel_types("Voice", "Stage")]
+    to: ChannelId,
+    #[description = "Let both channels hear each other"] bidirectional: Option<bool>,
+) -> Result {
+    if !authorize(ctx, Action::Link).await? {
+        return Ok(());
+    }
+    let res = (async {
+        let gid = ctx.guild_id().ok_or("not in guild")?;
+        let mode = if bidirectional.unwrap_or(false) {
+            LinkMode::Bidirectional
+        } else {
+            LinkMode::OneWay
+        };
+        ctx.data()
+            .command(AudioCommandPayload::Connect {
+                gid,
+                from_id: from,
+                to_gid: gid,
+                to_id: to,
+                mode,
+            })
+            .await
+            .map_err(error_message)?;
+        Ok(match mode {
+            LinkMode::OneWay => format!("Linked <#{}> to <#{}>", from, to),
+            LinkMode::Bidirectional => format!("Linked <#{}> and <#{}>", from, to),
+        })
+    })
+    .await;
+    reply(ctx, res).await;
+    Ok(())
+}
+
+#[poise::command(slash_command, guild_only)]
+#[tracing::instrument(name = "unlink", skip(ctx))]
+pub async fn unlink(
+    ctx: Ctx<'_>,
+    #[description = "Channel the voice is taken from"]
+    #[channel_types("Voice", "Stage")]
+    from: ChannelId,
+    #[description = "Channel the voice is played in"]
+    #[channel_types("Voice", "Stage")]
+    to: ChannelId,
+) -> Result {
+    if !authorize(ctx, Action::Link).await? {
+        return Ok(());
+    }
+    let res = (async {
+        let gid = ctx.guild_id().ok_or("not in guild")?;
+        ctx.data()
+            .command(AudioCommandPayload::Disconnect {
+                gid,
+                from_id: from,
+                to_id: to,
+            })
+            .await
+            .map_err(error_message)?;
+        Ok(format!("Unlinked <#{}> from <#{}>", from, to))
+    })
+    .await;
+    reply(ctx, res).await;
+    Ok(())
+}
+
+#[poise::command(slash_command, guild_only)]
+#[tracing::instrument(name = "hears", skip(ctx))]
+pub async fn hears(
+    ctx: Ctx<'_>,
+    #[description = "Voice channel to inspect (defaults to yours)"]
+    #[channel_types("Voice", "Stage")]
+    channel: Option<ChannelId>,
+) -> Result {
+    let res = (async {
+        let (guild_id, vc) = target_channel(ctx, channel)?;
+        let routes = ctx.data().routes.lock().await;
+        let mention = |ids: Vec<ChannelId>| {
+            if ids.is_empty() {
+                "nothing".to_string()
+            } else {
+                ids.iter()
+                    .map(|id| format!("<#{}>", id))
+                    .collect::<Vec<_>>()
+                    .join(", ")
             }
-            Err(AudioCommandError::BotUsedFull) => return Err("bot used full"),
-            Err(_) => return Err("unknown error"),
-            Ok(_) => Ok(vc),
-        }
+        };
+        Ok(format!(
+            "<#{}> hears {}\n<#{}> is heard in {}",
+            vc,
+            mention(routes.sources_of(guild_id, vc)),
+            vc,
+            mention(routes.destinations_of(guild_id, vc)),
+        ))
     })
     .await;
+    reply(ctx, res).await;
+    Ok(())
+}
 
-    let res = match res {
-        Err(e) => ctx.say(e).await,
-        Ok(id) => ctx.say(format!("Joined to <#{}>", id)).await,
+#[poise::command(slash_command, guild_only)]
+#[tracing::instrument(name = "status", skip(ctx))]
+pub async fn status(ctx: Ctx<'_>) -> Result {
+    let Some(gid) = ctx.guild_id() else {
+        return Ok(());
     };
-    if let Err(e) = res {
+    let bots = match ctx.data().status(gid).await {
+        Ok(bots) => bots,
+        Err(e) => {
+            reply(ctx, Err(error_message(e))).await;
+            return Ok(());
+        }
+    };
+    let channels = |ids: &[ChannelId]| {
+        if ids.is_empty() {
+            "nothing".to_string()
+        } else {
+            ids.iter()
+                .map(|id| format!("<#{}>", id))
+                .collect::<Vec<_>>()
+                .join(", ")
+        }
+    };
+    let mut embed = CreateEmbed::new().title("Bridge status");
+    if bots.is_empty() {
+        embed = embed.description("No bot is in a channel of this server");
+    }
+    for bot in &bots {
+        let speakers = if bot.speakers.is_empty() {
+            "nobody".to_string()
+        } else {
+            bot.speakers
+                .iter()
+                .map(|u| format!("<@{}>", u.0))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        let mode = if bot.opus { " (opus passthrough)" } else { "" };
+        let recording = if bot.recording.is_some() {
+            " (recording)"
+        } else {
+            ""
+        };
+        embed = embed.field(
+            format!("Bot #{}", bot.index),
+            format!(
+                "In <#{}>{}{}\nHears {}\nHeard in {}\nSpeaking: {}",
+                bot.channel,
+                mode,
+                recording,
+                channels(&bot.inbound),
+                channels(&bot.outbound),
+                speakers,
+            ),
+            false,
+        );
+    }
+    let volumes: Vec<_> = ctx
+        .data()
+        .volume_map
+        .get(&gid)
+        .map(|m| {
+            m.iter()
+                .map(|e| format!("<@{}> {:+.1} dB", e.key().0, e.value().db()))
+                .collect()
+        })
+        .unwrap_or_default();
+    if !volumes.is_empty() {
+        embed = embed.field("Volume overrides", volumes.join("\n"), false);
+    }
+    let capacity = ctx.data().pool.capacity(gid);
+    embed = embed.footer(CreateEmbedFooter::new(format!(
+        "{} of {} bots free in this server, {} of them connected",
+        capacity.free, capacity.total, capacity.healthy
+    )));
+    if let Err(e) = ctx.send(poise::CreateReply::default().embed(embed)).await {
         tracing::warn!("Failed to send message: {}", e);
     }
     Ok(())
 }
 
+#[poise::command(slash_command, guild_only)]
+#[tracing::instrument(name = "jitter", skip(ctx))]
+pub async fn jitter(
+    ctx: Ctx<'_>,
+    #[description = "Channel the voice is taken from"]
+    #[channel_types("Voice", "Stage")]
+    from: ChannelId,
+    #[description = "Channel the voice is played in"]
+    #[channel_types("Voice", "Stage")]
+    to: ChannelId,
+    #[description = "Latency to buffer before playing, in ms"]
+    #[min = 0]
+    #[max = 1000]
+    target: u32,
+    #[description = "Latency above which frames are dropped, in ms"]
+    #[min = 20]
+    #[max = 1000]
+    max: u32,
+) -> Result {
+    if !authorize(ctx, Action::Link).await? {
+        return Ok(());
+    }
+    let res = (async {
+        let gid = ctx.guild_id().ok_or("not in guild")?;
+        let config = JitterConfig::new(target, max).ok_or("target must not exceed max")?;
+        ctx.data()
+            .command(AudioCommandPayload::SetJitter {
+                gid,
+                from_id: from,
+                to_id: to,
+                config,
+            })
+            .await
+            .map_err(error_message)?;
+        Ok(format!(
+            "Buffering <#{}> in <#{}> for {} ms, at most {} ms",
+            from, to, target, max
+        ))
+    })
+    .await;
+    reply(ctx, res).await;
+    Ok(())
+}
+
+#[poise::command(
+    slash_command,
+    guild_only,
+    subcommands("speakers_allow", "speakers_deny", "speakers_reset", "speakers_show")
+)]
+pub async fn speakers(_: Ctx<'_>) -> Result {
+    Ok(())
+}
+
+/// Describes whose voice of `from` is played in `to`.
+fn describe_speakers(from: ChannelId, to: ChannelId, filter: &SpeakerFilter) -> String {
+    let users = |ids: &[serenity_voice_model::id::UserId]| {
+        ids.iter()
+            .map(|u| format!("<@{}>", u.0))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    let mut msg = if filter.allow.is_empty() {
+        format!("Everyone in <#{}> is heard in <#{}>", from, to)
+    } else {
+        format!(
+            "Only {} of <#{}> are heard in <#{}>",
+            users(&filter.allow),
+            from,
+            to
+        )
+    };
+    if !filter.deny.is_empty() {
+        msg.push_str(&format!(", never {}", users(&filter.deny)));
+    }
+    msg
+}
+
+/// Applies `change` to the speaker filter of the link from `from` into `to`,
+/// giving the filter now in place.
+async fn change_speakers(
+    ctx: Ctx<'_>,
+    from: ChannelId,
+    to: ChannelId,
+    change: impl FnOnce(&mut SpeakerFilter) -> bool,
+) -> std::result::Result<SpeakerFilter, &'static str> {
+    let gid = ctx.guild_id().ok_or("not in guild")?;
+    let mut filter = ctx.data().storage.speakers(from, to).await;
+    if !change(&mut filter) {
+        return Err("that user is not on the lists of this link");
+    }
+    ctx.data()
+        .command(AudioCommandPayload::SetSpeakers {
+            gid,
+            from_id: from,
+            to_id: to,
+            filter: filter.clone(),
+        })
+        .await
+        .map_err(error_message)?;
+    Ok(filter)
+}
+
+#[poise::command(slash_command, guild_only, rename = "allow")]
+#[tracing::instrument(name = "speakers_allow", skip(ctx, user), fields(user = user.id.get()))]
+pub async fn speakers_allow(
+    ctx: Ctx<'_>,
+    #[description = "Channel the voice is taken from"]
+    #[channel_types("Voice", "Stage")]
+    from: ChannelId,
+    #[description = "Channel the voice is played in"]
+    #[channel_types("Voice", "Stage")]
+    to: ChannelId,
+    #[description = "User to play; once anyone is allowed, nobody else is"] user: User,
+) -> Result {
+    if !authorize(ctx, Action::Link).await? {
+        return Ok(());
+    }
+    let uid = serenity_voice_model::id::UserId(user.id.get());
+    let res = change_speakers(ctx, from, to, |f| {
+        f.allow(uid);
+        true
+    })
+    .await
+    .map(|f| describe_speakers(from, to, &f));
+    reply(ctx, res).await;
+    Ok(())
+}
+
+#[poise::command(slash_command, guild_only, rename = "deny")]
+#[tracing::instrument(name = "speakers_deny", skip(ctx, user), fields(user = user.id.get()))]
+pub async fn speakers_deny(
+    ctx: Ctx<'_>,
+    #[description = "Channel the voice is taken from"]
+    #[channel_types("Voice", "Stage")]
+    from: ChannelId,
+    #[description = "Channel the voice is played in"]
+    #[channel_types("Voice", "Stage")]
+    to: ChannelId,
+    #[description = "User never to play"] user: User,
+) -> Result {
+    if !authorize(ctx, Action::Link).await? {
+        return Ok(());
+    }
+    let uid = serenity_voice_model::id::UserId(user.id.get());
+    let res = change_speakers(ctx, from, to, |f| {
+        f.deny(uid);
+        true
+    })
+    .await
+    .map(|f| describe_speakers(from, to, &f));
+    reply(ctx, res).await;
+    Ok(())
+}
+
+#[poise::command(slash_command, guild_only, rename = "reset")]
+#[tracing::instrument(name = "speakers_reset", skip(ctx, user), fields(user = user.as_ref().map(|u| u.id.get())))]
+pub async fn speakers_reset(
+    ctx: Ctx<'_>,
+    #[description = "Channel the voice is taken from"]
+    #[channel_types("Voice", "Stage")]
+    from: ChannelId,
+    #[description = "Channel the voice is played in"]
+    #[channel_types("Voice", "Stage")]
+    to: ChannelId,
+    #[description = "User to take off the lists (defaults to everyone)"] user: Option<User>,
+) -> Result {
+    if !authorize(ctx, Action::Link).await? {
+        return Ok(());
+    }
+    let uid = user.map(|u| serenity_voice_model::id::UserId(u.id.get()));
+    let res = change_speakers(ctx, from, to, |f| match uid {
+        Some(uid) => f.forget(uid),
+        None => {
+            *f = SpeakerFilter::default();
+            true
+        }
+    })
+    .await
+    .map(|f| describe_speakers(from, to, &f));
+    reply(ctx, res).await;
+    Ok(())
+}
+
+#[poise::command(slash_command, guild_only, rename = "show")]
+#[tracing::instrument(name = "speakers_show", skip(ctx))]
+pub async fn speakers_show(
+    ctx: Ctx<'_>,
+    #[description = "Channel the voice is taken from"]
+    #[channel_types("Voice", "Stage")]
+    from: ChannelId,
+    #[description = "Channel the voice is played in"]
+    #[channel_types("Voice", "Stage")]
+    to: ChannelId,
+) -> Result {
+    let filter = ctx.data().storage.speakers(from, to).await;
+    reply(ctx, Ok(describe_speakers(from, to, &filter))).await;
+    Ok(())
+}
+
+#[poise::command(
+    slash_command,
+    guild_only,
+    subcommands(
+        "broadcast_start",
+        "broadcast_add",
+        "broadcast_remove",
+        "broadcast_end",
+        "broadcast_list"
+    )
+)]
+pub async fn broadcast(_: Ctx<'_>) -> Result {
+    Ok(())
+}
+
+#[poise::command(slash_command, guild_only, rename = "start")]
+#[tracing::instrument(name = "broadcast_start", skip(ctx))]
+pub async fn broadcast_start(
+    ctx: Ctx<'_>,
+    #[description = "Name of the broadcast"]
+    #[max_length = 32]
+    name: String,
+    #[description = "Channel whose voice is broadcast (defaults to yours)"]
+    #[channel_types("Voice", "Stage")]
+    source: Option<ChannelId>,
+) -> Result {
+    if !authorize(ctx, Action::Link).await? {
+        return Ok(());
+    }
+    let res = (async {
+        let (gid, source) = target_channel(ctx, source)?;
+        ctx.data()
+            .command(AudioCommandPayload::StartBroadcast {
+                gid,
+                name: name.clone(),
+                source,
+            })
+            .await
+            .map_err(error_message)?;
+        Ok(format!(
+            "Broadcasting <#{}> as **{}**, add listening channels with /broadcast add",
+            source, name
+        ))
+    })
+    .await;
+    reply(ctx, res).await;
+    Ok(())
+}
+
+#[poise::command(slash_command, guild_only, rename = "add")]
+#[tracing::instrument(name = "broadcast_add", skip(ctx))]
+pub async fn broadcast_add(
+    ctx: Ctx<'_>,
+    #[description = "Name of the broadcast"] name: String,
+    #[description = "Channel to play the broadcast in"]
+    #[channel_types("Voice", "Stage")]
+    channel: ChannelId,
+) -> Result {
+    if !authorize(ctx, Action::Link).await? {
+        return Ok(());
+    }
+    let res = (async {
+        let gid = ctx.guild_id().ok_or("not in guild")?;
+        ctx.data()
+            .command(AudioCommandPayload::AddListener {
+                gid,
+                name: name.clone(),
+                cid: channel,
+            })
+            .await
+            .map_err(error_message)?;
+        Ok(format!("<#{}> now listens to **{}**", channel, name))
+    })
+    .await;
+    reply(ctx, res).await;
+    Ok(())
+}
+
+#[poise::command(slash_command, guild_only, rename = "remove")]
+#[tracing::instrument(name = "broadcast_remove", skip(ctx))]
+pub async fn broadcast_remove(
+    ctx: Ctx<'_>,
+    #[description = "Name of the broadcast"] name: String,
+    #[description = "Channel to stop playing the broadcast in"]
+    #[channel_types("Voice", "Stage")]
+    channel: ChannelId,
+) -> Result {
+    if !authorize(ctx, Action::Link).await? {
+        return Ok(());
+    }
+    let res = (async {
+        let gid = ctx.guild_id().ok_or("not in guild")?;
+        ctx.data()
+            .command(AudioCommandPayload::RemoveListener {
+                gid,
+                name: name.clone(),
+                cid: channel,
+            })
+            .await
+            .map_err(|e| match e {
+                AudioCommandError::ChannelNotFound => "that channel does not listen to it",
+                e => error_message(e),
+            })?;
+        Ok(format!("<#{}> no longer listens to **{}**", channel, name))
+    })
+    .await;
+    reply(ctx, res).await;
+    Ok(())
+}
+
+#[poise::command(slash_command, guild_only, rename = "end")]
+#[tracing::instrument(name = "broadcast_end", skip(ctx))]
+pub async fn broadcast_end(
+    ctx: Ctx<'_>,
+    #[description = "Name of the broadcast"] name: String,
+) -> Result {
+    if !authorize(ctx, Action::Link).await? {
+        return Ok(());
+    }
+    let res = (async {
+        let gid = ctx.guild_id().ok_or("not in guild")?;
+        ctx.data()
+            .command(AudioCommandPayload::EndBroadcast {
+                gid,
+                name: name.clone(),
+            })
+            .await
+            .map_err(error_message)?;
+        Ok(format!("Ended **{}**", name))
+    })
+    .await;
+    reply(ctx, res).await;
+    Ok(())
+}
+
+#[poise::command(slash_command, guild_only, rename = "list")]
+#[tracing::instrument(name = "broadcast_list", skip(ctx))]
+pub async fn broadcast_list(ctx: Ctx<'_>) -> Result {
+    let res = (async {
+        let gid = ctx.guild_id().ok_or("not in guild")?;
+        let routes = ctx.data().routes.lock().await;
+        let broadcasts = routes.broadcasts(gid);
+        if broadcasts.is_empty() {
+            return Ok("No broadcasts, start one with /broadcast start".to_string());
+        }
+        let lines: Vec<_> = broadcasts
+            .iter()
+            .map(|b| {
+                let listeners = if b.listeners.is_empty() {
+                    "nobody".to_string()
+                } else {
+                    b.listeners
+                        .iter()
+                        .map(|c| format!("<#{}>", c))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                };
+                format!("**{}**: <#{}> heard in {}", b.name, b.source, listeners)
+            })
+            .collect();
+        Ok(lines.join("\n"))
+    })
+    .await;
+    reply(ctx, res).await;
+    Ok(())
+}
+
+#[poise::command(slash_command, guild_only)]
+#[tracing::instrument(name = "volume", skip(ctx, user), fields(user = user.id.get()))]
+pub async fn volume(
+    ctx: Ctx<'_>,
+    #[description = "Whose voice to change"] user: User,
+    #[description = "Gain in dB, 0 resets, negative is quieter"]
+    #[min = -60]
+    #[max = 20]
+    gain: f64,
+) -> Result {
+    if user.id != ctx.author().id && !authorize(ctx, Action::Volume).await? {
+        return Ok(());
+    }
+    let res = (async {
+        let gid = ctx.guild_id().ok_or("not in guild")?;
+        let gain = Gain::from_db(gain as f32);
+        let uid = serenity_voice_model::id::UserId(user.id.get());
+        let gain = (gain != Gain::default()).then_some(gain);
+        let volume_map = Arc::clone(&ctx.data().volume_map.entry(gid).or_default());
+        match gain {
+            Some(gain) => {
+                volume_map.insert(uid, gain);
+            }
+            None => {
+                volume_map.remove(&uid);
+            }
+        }
+        ctx.data()
+            .storage
+            .update(|s| s.set_volume(gid, uid, gain))
+            .await;
+        let gain = gain.unwrap_or_default();
+        Ok(format!("Set **{}** to {:+.1} dB", user.name, gain.db()))
+    })
+    .await;
+    reply(ctx, res).await;
+    Ok(())
+}
+
+#[poise::command(
+    slash_command,
+    guild_only,
+    subcommands("bridge_role_add", "bridge_role_remove", "bridge_role_list"),
+    default_member_permissions = "MANAGE_GUILD"
+)]
+pub async fn bridge_role(_: Ctx<'_>) -> Result {
+    Ok(())
+}
+
+#[poise::command(slash_command, guild_only, rename = "add")]
+#[tracing::instrument(name = "bridge_role_add", skip(ctx, role), fields(role = role.id.get()))]
+pub async fn bridge_role_add(
+    ctx: Ctx<'_>,
+    #[description = "Role whose members may join, link and leave"] role: Role,
+) -> Result {
+    if !authorize(ctx, Action::Configure).await? {
+        return Ok(());
+    }
+    let res = (async {
+        let gid = ctx.guild_id().ok_or("not in guild")?;
+        let added = ctx
+            .data()
+            .storage
+            .update(|s| s.add_role(gid, role.id))
+            .await;
+        if !added {
+            return Err("that already is a bridge role");
+        }
+        Ok(format!("<@&{}> may now run bridge commands", role.id))
+    })
+    .await;
+    reply(ctx, res).await;
+    Ok(())
+}
+
+#[poise::command(slash_command, guild_only, rename = "remove")]
+#[tracing::instrument(name = "bridge_role_remove", skip(ctx, role), fields(role = role.id.get()))]
+pub async fn bridge_role_remove(
+    ctx: Ctx<'_>,
+    #[description = "Role to take bridge commands from"] role: Role,
+) -> Result {
+    if !authorize(ctx, Action::Configure).await? {
+        return Ok(());
+    }
+    let res = (async {
+        let gid = ctx.guild_id().ok_or("not in guild")?;
+        let removed = ctx
+            .data()
+            .storage
+            .update(|s| s.remove_role(gid, role.id))
+            .await;
+        if !removed {
+            return Err("that is not a bridge role");
+        }
+        Ok(format!("<@&{}> may no longer run bridge commands", role.id))
+    })
+    .await;
+    reply(ctx, res).await;
+    Ok(())
+}
+
+#[poise::command(slash_command, guild_only, rename = "list")]
+#[tracing::instrument(name = "bridge_role_list", skip(ctx))]
+pub async fn bridge_role_list(ctx: Ctx<'_>) -> Result {
+    let res = (async {
+        let gid = ctx.guild_id().ok_or("not in guild")?;
+        let roles = ctx.data().storage.roles(gid).await;
+        if roles.is_empty() {
+            return Ok(
+                "No bridge roles, only members with Move Members may run bridge commands"
+                    .to_string(),
+            );
+        }
+        let roles: Vec<_> = roles.iter().map(|r| format!("<@&{}>", r)).collect();
+        Ok(format!("Bridge roles: {}", roles.join(", ")))
+    })
+    .await;
+    reply(ctx, res).await;
+    Ok(())
+}
+
+#[poise::command(slash_command, guild_only, subcommands("record_start", "record_stop"))]
+pub async fn record(_: Ctx<'_>) -> Result {
+    Ok(())
+}
+
+/// Posts `msg` in the text chat of voice channel `vc`, so that everyone in
+/// it sees it, unless the command was run there already.
+async fn announce(ctx: Ctx<'_>, vc: ChannelId, msg: &str) {
+    if ctx.channel_id() == vc {
+        return;
+    }
+    if let Err(e) = vc.say(ctx.http(), msg).await {
+        tracing::warn!("Failed to announce in {}: {}", vc, e);
+    }
+}
+
+#[poise::command(slash_command, guild_only, rename = "start")]
+#[tracing::instrument(name = "record_start", skip(ctx))]
+pub async fn record_start(
+    ctx: Ctx<'_>,
+    #[description = "Voice channel to record (defaults to yours)"]
+    #[channel_types("Voice", "Stage")]
+    channel: Option<ChannelId>,
+    #[description = "One mixed file, or one file per user (default mixed)"] layout: Option<Layout>,
+    #[description = "File format (default ogg/opus)"] format: Option<Format>,
+) -> Result {
+    if !authorize(ctx, Action::Record).await? {
+        return Ok(());
+    }
+    let res = (async {
+        let (gid, vc) = target_channel(ctx, channel)?;
+        let options = RecordOptions {
+            layout: layout.unwrap_or(Layout::Mixed),
+            format: format.unwrap_or(Format::Opus),
+        };
+        ctx.data()
+            .command(AudioCommandPayload::StartRecording {
+                gid,
+                cid: vc,
+                options,
+            })
+            .await
+            .map_err(error_message)?;
+        Ok(vc)
+    })
+    .await;
+    match res {
+        Ok(vc) => {
+            let msg = format!(
+                "🔴 <#{}> is being recorded, started by <@{}>",
+                vc,
+                ctx.author().id
+            );
+            announce(ctx, vc, &msg).await;
+            reply(ctx, Ok(msg)).await;
+        }
+        Err(e) => reply(ctx, Err(e)).await,
+    }
+    Ok(())
+}
+
+#[poise::command(slash_command, guild_only, rename = "stop")]
+#[tracing::instrument(name = "record_stop", skip(ctx))]
+pub async fn record_stop(
+    ctx: Ctx<'_>,
+    #[description = "Voice channel to stop recording (defaults to yours)"]
+    #[channel_types("Voice", "Stage")]
+    channel: Option<ChannelId>,
+) -> Result {
+    if !authorize(ctx, Action::Record).await? {
+        return Ok(());
+    }
+    let res = (async {
+        let (gid, vc) = target_channel(ctx, channel)?;
+        let dir = ctx
+            .data()
+            .stop_recording(gid, vc)
+            .await
+            .map_err(error_message)?;
+        Ok((vc, dir))
+    })
+    .await;
+    match res {
+        Ok((vc, dir)) => {
+            announce(ctx, vc, &format!("⏹️ <#{}> is no longer recorded", vc)).await;
+            let msg = format!(
+                "⏹️ <#{}> is no longer recorded, saved to `{}`",
+                vc,
+                dir.display()
+            );
+            reply(ctx, Ok(msg)).await;
+        }
+        Err(e) => reply(ctx, Err(e)).await,
+    }
+    Ok(())
+}
+
+/// Parses a guild or channel id given as text, as ids don't fit an integer
+/// option.
+fn parse_id(id: &str) -> std::result::Result<u64, &'static str> {
+    id.trim()
+        .parse()
+        .ok()
+        .filter(|&id| id != 0)
+        .ok_or("that is not an id, copy it with developer mode on")
+}
+
+#[poise::command(
+    slash_command,
+    guild_only,
+    subcommands("peer_allow", "peer_revoke", "peer_list", "peer_link", "peer_unlink")
+)]
+pub async fn peer(_: Ctx<'_>) -> Result {
+    Ok(())
+}
+
+#[poise::command(slash_command, guild_only, rename = "allow")]
+#[tracing::instrument(name = "peer_allow", skip(ctx))]
+pub async fn peer_allow(
+    ctx: Ctx<'_>,
+    #[description = "Id of the server whose channels may be linked with ours"] guild: String,
+) -> Result {
+    if !authorize(ctx, Action::Configure).await? {
+        return Ok(());
+    }
+    let res = (async {
+        let gid = ctx.guild_id().ok_or("not in guild")?;
+        let peer = GuildId::new(parse_id(&guild)?);
+        if peer == gid {
+            return Err("that is this server");
+        }
+        let storage = &ctx.data().storage;
+        if !storage.update(|s| s.add_peer(gid, peer)).await {
+            return Err("that server already is allowed");
+        }
+        Ok(if storage.peered(gid, peer).await {
+            format!("Channels of server {} may now be linked with ours", peer)
+        } else {
+            format!(
+                "Allowed server {}, links work once it runs /peer allow {} too",
+                peer, gid
+            )
+        })
+    })
+    .await;
+    reply(ctx, res).await;
+    Ok(())
+}
+
+#[poise::command(slash_command, guild_only, rename = "revoke")]
+#[tracing::instrument(name = "peer_revoke", skip(ctx))]
+pub async fn peer_revoke(
+    ctx: Ctx<'_>,
+    #[description = "Id of the server to stop linking with"] guild: String,
+) -> Result {
+    if !authorize(ctx, Action::Configure).await? {
+        return Ok(());
+    }
+    let res = (async {
+        let gid = ctx.guild_id().ok_or("not in guild")?;
+        let peer = GuildId::new(parse_id(&guild)?);
+        let data = ctx.data();
+        let links: Vec<_> = data
+            .storage
+            .state()
+            .await
+            .links
+            .into_iter()
+            .filter(|l| l.guild != l.to_guild() && l.between(gid, peer))
+            .collect();
+        for l in &links {
+            let payload = AudioCommandPayload::Disconnect {
+                gid,
+                from_id: l.from,
+                to_id: l.to,
+            };
+            // suspended links are not live, they are dropped from storage below
+            let _ = data.command(payload).await;
+        }
+        if !data.storage.update(|s| s.remove_peer(gid, peer)).await {
+            return Err("that server was not allowed");
+        }
+        Ok(format!(
+            "Server {} may no longer be linked with ours, removed {} links",
+            peer,
+            links.len()
+        ))
+    })
+    .await;
+    reply(ctx, res).await;
+    Ok(())
+}
+
+#[poise::command(slash_command, guild_only, rename = "list")]
+#[tracing::instrument(name = "peer_list", skip(ctx))]
+pub async fn peer_list(ctx: Ctx<'_>) -> Result {
+    let res = (async {
+        let gid = ctx.guild_id().ok_or("not in guild")?;
+        let storage = &ctx.data().storage;
+        let peers = storage.peers(gid).await;
+        if peers.is_empty() {
+            return Ok("No other server may be linked with ours".to_string());
+        }
+        let mut lines = Vec::new();
+        for peer in peers {
+            let state = if storage.peered(gid, peer).await {
+                "linkable"
+            } else {
+                "waiting for their /peer allow"
+            };
+            lines.push(format!("{}: {}", peer, state));
+        }
+        Ok(lines.join("\n"))
+    })
+    .await;
+    reply(ctx, res).await;
+    Ok(())
+}
+
+#[poise::command(slash_command, guild_only, rename = "link")]
+#[tracing::instrument(name = "peer_link", skip(ctx))]
+pub async fn peer_link(
+    ctx: Ctx<'_>,
+    #[description = "Channel of ours to take the voice from"]
+    #[channel_types("Voice", "Stage")]
+    from: ChannelId,
+    #[description = "Id of the other server"] guild: String,
+    #[description = "Id of its channel to play the voice in"] channel: String,
+    #[description = "Let both channels hear each other"] bidirectional: Option<bool>,
+) -> Result {
+    if !authorize(ctx, Action::Link).await? {
+        return Ok(());
+    }
+    let res = (async {
+        let gid = ctx.guild_id().ok_or("not in guild")?;
+        let to_gid = GuildId::new(parse_id(&guild)?);
+        let to = ChannelId::new(parse_id(&channel)?);
+        let mode = if bidirectional.unwrap_or(false) {
+            LinkMode::Bidirectional
+        } else {
+            LinkMode::OneWay
+        };
+        ctx.data()
+            .command(AudioCommandPayload::Connect {
+                gid,
+                from_id: from,
+                to_gid,
+                to_id: to,
+                mode,
+            })
+            .await
+            .map_err(error_message)?;
+        Ok(match mode {
+            LinkMode::OneWay => format!("Linked <#{}> to <#{}> of server {}", from, to, to_gid),
+            LinkMode::Bidirectional => {
+                format!("Linked <#{}> and <#{}> of server {}", from, to, to_gid)
+            }
+        })
+    })
+    .await;
+    reply(ctx, res).await;
+    Ok(())
+}
+
+#[poise::command(slash_command, guild_only, rename = "unlink")]
+#[tracing::instrument(name = "peer_unlink", skip(ctx))]
+pub async fn peer_unlink(
+    ctx: Ctx<'_>,
+    #[description = "Channel of ours"]
+    #[channel_types("Voice", "Stage")]
+    channel: ChannelId,
+    #[description = "Id of the channel of the other server"] other: String,
+) -> Result {
+    if !authorize(ctx, Action::Link).await? {
+        return Ok(());
+    }
+    let res = (async {
+        let gid = ctx.guild_id().ok_or("not in guild")?;
+        let other = ChannelId::new(parse_id(&other)?);
+        // either server may have made the link, in either direction
+        for (from_id, to_id) in [(channel, other), (other, channel)] {
+            let payload = AudioCommandPayload::Disconnect {
+                gid,
+                from_id,
+                to_id,
+            };
+            match ctx.data().command(payload).await {
+                Ok(()) => return Ok(format!("Unlinked <#{}> and <#{}>", channel, other)),
+                Err(AudioCommandError::LinkNotFound) => continue,
+                Err(e) => return Err(error_message(e)),
+            }
+        }
+        Err(error_message(AudioCommandError::LinkNotFound))
+    })
+    .await;
+    reply(ctx, res).await;
+    Ok(())
+}
+
 #[poise::command(slash_command)]
 #[tracing::instrument(name="ping", skip(ctx), fields(author=ctx.author().id.get()))]
 pub async fn ping(ctx: Ctx<'_>) -> Result {
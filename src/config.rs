@@ -0,0 +1,503 @@
+//! Startup settings, read from a TOML file and overridden by the environment.
+//!
+//! The file is `voisinc.toml`, or whatever `VOISINC_CONFIG` names. Every
+//! setting has a default, so it may be left out entirely:
+//!
+//! ```toml
+//! state = "voisinc-state.json"
+//!
+//! [bots]
+//! tokens = ["..."]
+//! token_files = ["/run/secrets/voisinc-bot-1"]
+//!
+//! [commands]
+//! # register the commands in these guilds only, instead of globally
+//! guilds = [123456789012345678]
+//!
+//! [audio]
+//! command_queue = 10
+//! idle_timeout_secs = 300
+//! opus_passthrough = false
+//! jitter = { target_ms = 60, max_ms = 200 }
+//!
+//! [log]
+//! level = "info,voisinc::audio=debug"
+//! format = "json"
+//!
+//! [listen]
+//! metrics = "127.0.0.1:9100"
+//! api = "127.0.0.1:9200"
+//! socket = "/run/voisinc/control.sock"
+//!
+//! [api]
+//! # bearer token the control API requires, see `crate::api`
+//! token_file = "/run/secrets/voisinc-api"
+//!
+//! [record]
+//! dir = "recordings"
+//! ```
+
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde::Deserialize;
+use serenity::model::id::GuildId;
+use thiserror::Error;
+use tracing_subscriber::EnvFilter;
+
+use crate::jitter::JitterConfig;
+
+/// Where the config is read from when `VOISINC_CONFIG` is not set.
+const DEFAULT_PATH: &str = "voisinc.toml";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// file the bridge topology is saved to, see [`crate::storage`].
+    pub state: PathBuf,
+    pub bots: Bots,
+    pub commands: Commands,
+    pub audio: Audio,
+    pub log: Log,
+    pub listen: Listen,
+    pub api: Api,
+    pub record: Record,
+}
+
+/// The bot pool, one bot per token.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Bots {
+    pub tokens: Vec<String>,
+    /// files holding one token each, e.g. mounted secrets; their tokens
+    /// come after `tokens`.
+    pub token_files: Vec<PathBuf>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Commands {
+    /// guilds to register the slash commands in; globally if empty.
+    pub guilds: Vec<GuildId>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Audio {
+    /// audio commands queued before commands have to wait.
+    pub command_queue: usize,
+    /// how long a bridged channel may stay empty before it is suspended.
+    pub idle_timeout_secs: u64,
+    /// play a lone source as Opus, see [`crate::passthrough`].
+    pub opus_passthrough: bool,
+    /// jitter buffer of links that have none of their own.
+    pub jitter: JitterConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Log {
+    /// an [`EnvFilter`] directive.
+    pub level: String,
+    pub format: LogFormat,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// one JSON object per line, with the spans it happened in.
+    Json,
+    /// human readable, for a terminal.
+    Pretty,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Listen {
+    /// address to serve Prometheus metrics on, see [`crate::metrics`].
+    pub metrics: Option<SocketAddr>,
+    /// address to serve the control API on, see [`crate::api`].
+    pub api: Option<SocketAddr>,
+    /// Unix socket to serve the control API on without a token, for
+    /// `voisinc-ctl`.
+    pub socket: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Api {
+    /// bearer token every request must carry.
+    pub token: String,
+    /// file holding the token instead.
+    pub token_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Record {
+    /// directory recordings are saved under, one directory each.
+    pub dir: PathBuf,
+}
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("failed to read {path}: {source}")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("invalid config {path}: {source}")]
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+    #[error("invalid {var}={value:?}, expected {expected}")]
+    Env {
+        var: &'static str,
+        value: String,
+        expected: &'static str,
+    },
+    #[error("failed to read token file {path}: {source}")]
+    TokenFile {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("no bot token, set DISCORD_TOKEN or bots.tokens")]
+    NoTokens,
+    #[error("bot token #{0} is empty")]
+    EmptyToken(usize),
+    #[error("audio.command_queue must be at least 1")]
+    CommandQueue,
+    #[error("audio.jitter: target_ms must not exceed max_ms, which must not exceed {max}")]
+    Jitter { max: u32 },
+    #[error("the control API needs a token, set VOISINC_API_TOKEN or api.token")]
+    ApiToken,
+    #[error("invalid log.level {level:?}: {reason}")]
+    LogLevel { level: String, reason: String },
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            state: "voisinc-state.json".into(),
+            bots: Bots::default(),
+            commands: Commands::default(),
+            audio: Audio::default(),
+            log: Log::default(),
+            listen: Listen::default(),
+            api: Api::default(),
+            record: Record::default(),
+        }
+    }
+}
+
+impl Default for Record {
+    fn default() -> Self {
+        Self {
+            dir: "recordings".into(),
+        }
+    }
+}
+
+impl Default for Audio {
+    fn default() -> Self {
+        Self {
+            command_queue: 10,
+            idle_timeout_secs: 300,
+            opus_passthrough: false,
+            jitter: JitterConfig::BUILTIN,
+        }
+    }
+}
+
+impl Default for Log {
+    fn default() -> Self {
+        Self {
+            level: "info".into(),
+            format: LogFormat::Json,
+        }
+    }
+}
+
+impl Config {
+    /// Reads the config file, applies the environment and checks the result.
+    ///
+    /// A missing `voisinc.toml` gives the defaults, a missing file named by
+    /// `VOISINC_CONFIG` is an error. Token files are read in here, so
+    /// [`Bots::tokens`] and [`Api::token`] hold the tokens afterwards.
+    pub fn load() -> Result<Self, ConfigError> {
+        let (path, required) = match std::env::var_os("VOISINC_CONFIG") {
+            Some(path) => (PathBuf::from(path), true),
+            None => (PathBuf::from(DEFAULT_PATH), false),
+        };
+        let mut config = match std::fs::read_to_string(&path) {
+            Ok(text) => Self::parse(&path, &text)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && !required => Self::default(),
+            Err(source) => return Err(ConfigError::Read { path, source }),
+        };
+        config.apply_env(|var| std::env::var(var).ok())?;
+        config.read_token_files()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn parse(path: &Path, text: &str) -> Result<Self, ConfigError> {
+        toml::from_str(text).map_err(|source| ConfigError::Parse {
+            path: path.into(),
+            source,
+        })
+    }
+
+    /// Overrides settings from the environment variables that are set, as
+    /// `var` looks them up.
+    fn apply_env(&mut self, var: impl Fn(&str) -> Option<String>) -> Result<(), ConfigError> {
+        if let Some(tokens) = var("DISCORD_TOKEN") {
+            self.bots.tokens = tokens.split(';').map(str::to_string).collect();
+            self.bots.token_files.clear();
+        }
+        if let Some(path) = var("VOISINC_STATE") {
+            self.state = path.into();
+        }
+        if let Some(guilds) = env(&var, "VOISINC_GUILDS", "comma separated guild ids", |s| {
+            s.split(',')
+                .map(|g| g.trim().parse().ok().filter(|&g| g != 0).map(GuildId::new))
+                .collect()
+        })? {
+            self.commands.guilds = guilds;
+        }
+        if let Some(secs) = env(&var, "VOISINC_IDLE_TIMEOUT", "seconds", |s| s.parse().ok())? {
+            self.audio.idle_timeout_secs = secs;
+        }
+        if let Some(on) = env(
+            &var,
+            "VOISINC_OPUS_PASSTHROUGH",
+            "1, true, 0 or false",
+            |s| match s {
+                "1" | "true" => Some(true),
+                "0" | "false" => Some(false),
+                _ => None,
+            },
+        )? {
+            self.audio.opus_passthrough = on;
+        }
+        if let Some(dir) = var("VOISINC_RECORD_DIR") {
+            self.record.dir = dir.into();
+        }
+        if let Some(level) = var("VOISINC_LOG") {
+            self.log.level = level;
+        }
+        if let Some(format) = env(&var, "VOISINC_LOG_FORMAT", "json or pretty", |s| match s {
+            "json" => Some(LogFormat::Json),
+            "pretty" => Some(LogFormat::Pretty),
+            _ => None,
+        })? {
+            self.log.format = format;
+        }
+        if let Some(addr) = env(
+            &var,
+            "VOISINC_METRICS_ADDR",
+            "an address like 127.0.0.1:9100",
+            |s| SocketAddr::from_str(s).ok(),
+        )? {
+            self.listen.metrics = Some(addr);
+        }
+        if let Some(addr) = env(
+            &var,
+            "VOISINC_API_ADDR",
+            "an address like 127.0.0.1:9200",
+            |s| SocketAddr::from_str(s).ok(),
+        )? {
+            self.listen.api = Some(addr);
+        }
+        if let Some(path) = var("VOISINC_SOCKET") {
+            self.listen.socket = Some(path.into());
+        }
+        if let Some(token) = var("VOISINC_API_TOKEN") {
+            self.api.token = token;
+            self.api.token_file = None;
+        }
+        Ok(())
+    }
+
+    fn read_token_files(&mut self) -> Result<(), ConfigError> {
+        for path in self.bots.token_files.drain(..) {
+            match std::fs::read_to_string(&path) {
+                Ok(token) => self.bots.tokens.push(token.trim().to_string()),
+                Err(source) => return Err(ConfigError::TokenFile { path, source }),
+            }
+        }
+        if let Some(path) = self.api.token_file.take() {
+            match std::fs::read_to_string(&path) {
+                Ok(token) => self.api.token = token.trim().to_string(),
+                Err(source) => return Err(ConfigError::TokenFile { path, source }),
+            }
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.bots.tokens.is_empty() {
+            return Err(ConfigError::NoTokens);
+        }
+        if let Some(i) = self.bots.tokens.iter().position(|t| t.trim().is_empty()) {
+            return Err(ConfigError::EmptyToken(i));
+        }
+        if self.listen.api.is_some() && self.api.token.trim().is_empty() {
+            return Err(ConfigError::ApiToken);
+        }
+        if self.audio.command_queue == 0 {
+            return Err(ConfigError::CommandQueue);
+        }
+        let jitter = self.audio.jitter;
+        if JitterConfig::new(jitter.target_ms, jitter.max_ms).is_none() {
+            return Err(ConfigError::Jitter {
+                max: JitterConfig::MAX_MS,
+            });
+        }
+        if let Err(e) = EnvFilter::try_new(&self.log.level) {
+            return Err(ConfigError::LogLevel {
+                level: self.log.level.clone(),
+                reason: e.to_string(),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Parses the environment variable `name` if `var` finds it set.
+fn env<T>(
+    var: impl Fn(&str) -> Option<String>,
+    name: &'static str,
+    expected: &'static str,
+    parse: impl FnOnce(&str) -> Option<T>,
+) -> Result<Option<T>, ConfigError> {
+    let Some(value) = var(name) else {
+        return Ok(None);
+    };
+    match parse(value.trim()) {
+        Some(v) => Ok(Some(v)),
+        None => Err(ConfigError::Env {
+            var: name,
+            value,
+            expected,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn parse(text: &str) -> Config {
+        Config::parse(Path::new("voisinc.toml"), text).unwrap()
+    }
+
+    /// Applies `vars` as the whole environment.
+    fn with_env(mut config: Config, vars: &[(&str, &str)]) -> Result<Config, ConfigError> {
+        let vars: HashMap<_, _> = vars.iter().copied().collect();
+        config.apply_env(|var| vars.get(var).map(|v| v.to_string()))?;
+        Ok(config)
+    }
+
+    fn invalid(text: &str) -> String {
+        parse(text).validate().unwrap_err().to_string()
+    }
+
+    #[test]
+    fn parses_sections_and_defaults() {
+        let config = parse(
+            r#"
+            [bots]
+            tokens = ["a", "b"]
+
+            [audio]
+            jitter = { target_ms = 40, max_ms = 100 }
+
+            [listen]
+            api = "127.0.0.1:9200"
+            "#,
+        );
+        assert_eq!(config.bots.tokens, ["a", "b"]);
+        assert_eq!(config.audio.jitter, JitterConfig::new(40, 100).unwrap());
+        assert_eq!(config.audio.command_queue, 10);
+        assert_eq!(config.listen.api, Some("127.0.0.1:9200".parse().unwrap()));
+        assert_eq!(config.state, Path::new("voisinc-state.json"));
+
+        let err = Config::parse(Path::new("voisinc.toml"), "[audio]\nvolume = 3\n").unwrap_err();
+        assert!(err.to_string().starts_with("invalid config voisinc.toml: "));
+    }
+
+    #[test]
+    fn validate_names_what_is_wrong() {
+        assert_eq!(
+            invalid(""),
+            "no bot token, set DISCORD_TOKEN or bots.tokens"
+        );
+        assert_eq!(
+            invalid("bots.tokens = [\"a\", \" \"]"),
+            "bot token #1 is empty"
+        );
+        assert_eq!(
+            invalid("bots.tokens = [\"a\"]\nlisten.api = \"127.0.0.1:9200\""),
+            "the control API needs a token, set VOISINC_API_TOKEN or api.token"
+        );
+        assert_eq!(
+            invalid("bots.tokens = [\"a\"]\naudio.command_queue = 0"),
+            "audio.command_queue must be at least 1"
+        );
+        assert_eq!(
+            invalid("bots.tokens = [\"a\"]\naudio.jitter = { target_ms = 60, max_ms = 2000 }"),
+            "audio.jitter: target_ms must not exceed max_ms, which must not exceed 1000"
+        );
+        assert!(invalid("bots.tokens = [\"a\"]\nlog.level = \"info,=\"")
+            .starts_with("invalid log.level \"info,=\": "));
+        assert!(parse("bots.tokens = [\"a\"]").validate().is_ok());
+    }
+
+    #[test]
+    fn the_environment_wins_over_the_file() {
+        let file = parse(
+            r#"
+            state = "file.json"
+            bots.token_files = ["/run/secrets/bot"]
+            api.token_file = "/run/secrets/api"
+            log.format = "pretty"
+            "#,
+        );
+        let config = with_env(
+            file.clone(),
+            &[
+                ("DISCORD_TOKEN", "a;b"),
+                ("VOISINC_STATE", "env.json"),
+                ("VOISINC_GUILDS", "1, 2"),
+                ("VOISINC_OPUS_PASSTHROUGH", "true"),
+                ("VOISINC_LOG_FORMAT", "json"),
+                ("VOISINC_API_TOKEN", "secret"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(config.bots.tokens, ["a", "b"]);
+        assert!(config.bots.token_files.is_empty());
+        assert_eq!(config.state, Path::new("env.json"));
+        assert_eq!(config.commands.guilds, [GuildId::new(1), GuildId::new(2)]);
+        assert!(config.audio.opus_passthrough);
+        assert_eq!(config.log.format, LogFormat::Json);
+        assert_eq!(config.api.token, "secret");
+        assert_eq!(config.api.token_file, None);
+
+        // unset variables leave the file alone
+        let config = with_env(file.clone(), &[]).unwrap();
+        assert_eq!(config.state, Path::new("file.json"));
+        assert_eq!(config.log.format, LogFormat::Pretty);
+        assert_eq!(config.bots.token_files.len(), 1);
+
+        let err = with_env(file, &[("VOISINC_GUILDS", "1,0")]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid VOISINC_GUILDS=\"1,0\", expected comma separated guild ids"
+        );
+    }
+}
@@ -0,0 +1,216 @@
+use std::sync::{Mutex, OnceLock};
+
+use crossbeam_queue::ArrayQueue;
+use serde::{Deserialize, Serialize};
+
+use crate::mixer::Voice;
+
+/// Length of one voice frame.
+pub const FRAME_MS: u32 = 20;
+
+/// Frames of loss concealment played before falling back to silence.
+const MAX_CONCEALED: u32 = 2;
+
+/// Weight of the newest sample in the running average of the buffer depth.
+const DEPTH_SMOOTHING: f32 = 0.05;
+
+/// The configured [`JitterConfig::default`], see [`JitterConfig::set_default`].
+static DEFAULT: OnceLock<JitterConfig> = OnceLock::new();
+
+/// How much voice a link keeps queued before playing it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JitterConfig {
+    /// latency aimed for; playback starts once this much is buffered.
+    pub target_ms: u32,
+    /// the oldest frames are dropped beyond this.
+    pub max_ms: u32,
+}
+
+impl JitterConfig {
+    pub const MAX_MS: u32 = 1000;
+    /// The default unless configured otherwise.
+    pub const BUILTIN: Self = Self {
+        target_ms: 60,
+        max_ms: 200,
+    };
+
+    pub fn new(target_ms: u32, max_ms: u32) -> Option<Self> {
+        (target_ms <= max_ms && max_ms <= Self::MAX_MS).then_some(Self { target_ms, max_ms })
+    }
+    pub fn target_frames(&self) -> usize {
+        (self.target_ms / FRAME_MS) as usize
+    }
+    pub fn max_frames(&self) -> usize {
+        ((self.max_ms / FRAME_MS) as usize).max(1)
+    }
+    /// Makes `config` the default of links without their own; only the first
+    /// call, made at startup, counts.
+    pub fn set_default(config: Self) {
+        let _ = DEFAULT.set(config);
+    }
+}
+
+impl Default for JitterConfig {
+    fn default() -> Self {
+        DEFAULT.get().copied().unwrap_or(Self::BUILTIN)
+    }
+}
+
+/// Smooths out the arrival of one source's voice.
+///
+/// Playback starts once [`JitterConfig::target_ms`] is queued. A short
+/// underrun is concealed by fading out the last frame, a longer one makes the
+/// buffer fill up to the target again. When the source's clock runs faster
+/// than ours the queue keeps growing, so a frame is skipped whenever its
+/// average depth drifts above the target.
+#[derive(Debug)]
+pub struct JitterBuffer {
+    queue: ArrayQueue<Voice>,
+    config: JitterConfig,
+    /// only taken by the reader, so never contended; pushing skips it.
+    playout: Mutex<Playout>,
+}
+
+/// What the reader remembers between two pops.
+#[derive(Debug, Default)]
+struct Playout {
+    playing: bool,
+    last: Option<Voice>,
+    concealed: u32,
+    depth: f32,
+}
+
+impl JitterBuffer {
+    pub fn new(config: JitterConfig) -> Self {
+        Self {
+            queue: ArrayQueue::new(config.max_frames()),
+            config,
+            playout: Default::default(),
+        }
+    }
+
+    /// Queues `voice`, `true` if the oldest frame was dropped to make room.
+    pub fn push(&self, voice: Voice) -> bool {
+        self.queue.force_push(voice).is_some()
+    }
+
+    /// The voice to play on this tick, `None` meaning silence.
+    pub fn pop(&self) -> Option<Voice> {
+        let mut playout = self.playout.lock().unwrap();
+        if !playout.playing {
+            if self.queue.len() < self.config.target_frames().max(1) {
+                return None;
+            }
+            playout.playing = true;
+            playout.depth = self.queue.len() as f32;
+        }
+        let Some(mut voice) = self.queue.pop() else {
+            return playout.conceal();
+        };
+        playout.depth += (self.queue.len() as f32 - playout.depth) * DEPTH_SMOOTHING;
+        if playout.depth > (self.config.target_frames() + 1) as f32 {
+            if let Some(next) = self.queue.pop() {
+                voice = next;
+                playout.depth -= 1.0;
+            }
+        }
+        playout.concealed = 0;
+        playout.last = Some(voice.clone());
+        Some(voice)
+    }
+}
+
+impl Playout {
+    /// Repeats the last frame at half the level each time, then rebuffers.
+    fn conceal(&mut self) -> Option<Voice> {
+        if self.concealed >= MAX_CONCEALED {
+            self.playing = false;
+            self.last = None;
+            return None;
+        }
+        self.concealed += 1;
+        let shift = self.concealed;
+        let last = self.last.as_ref()?;
+        Some(Voice::pcm(last.pcm.iter().map(|x| x >> shift).collect()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(level: i32) -> Voice {
+        Voice::pcm(vec![level; 4].into())
+    }
+
+    /// The level of what `pop` gave, `None` for silence.
+    fn pop(buffer: &JitterBuffer) -> Option<i32> {
+        buffer.pop().map(|voice| voice.pcm[0])
+    }
+
+    #[test]
+    fn config_is_clamped() {
+        assert!(JitterConfig::new(60, 200).is_some());
+        assert!(JitterConfig::new(1000, JitterConfig::MAX_MS).is_some());
+        assert_eq!(JitterConfig::new(60, 1001), None);
+        assert_eq!(JitterConfig::new(300, 200), None);
+
+        let config = JitterConfig::new(0, 0).unwrap();
+        assert_eq!((config.target_frames(), config.max_frames()), (0, 1));
+    }
+
+    #[test]
+    fn playback_starts_at_the_target() {
+        let buffer = JitterBuffer::new(JitterConfig::new(60, 200).unwrap());
+        buffer.push(frame(1));
+        buffer.push(frame(2));
+        assert_eq!(pop(&buffer), None);
+        buffer.push(frame(3));
+        assert_eq!(pop(&buffer), Some(1));
+        assert_eq!(pop(&buffer), Some(2));
+    }
+
+    #[test]
+    fn the_oldest_frame_is_dropped_when_full() {
+        let buffer = JitterBuffer::new(JitterConfig::new(40, 40).unwrap());
+        assert!(!buffer.push(frame(1)));
+        assert!(!buffer.push(frame(2)));
+        assert!(buffer.push(frame(3)));
+        assert_eq!(pop(&buffer), Some(2));
+    }
+
+    #[test]
+    fn underruns_fade_out_then_rebuffer() {
+        let buffer = JitterBuffer::new(JitterConfig::new(60, 200).unwrap());
+        for _ in 0..3 {
+            buffer.push(frame(1000));
+        }
+        for _ in 0..3 {
+            assert_eq!(pop(&buffer), Some(1000));
+        }
+        assert_eq!(pop(&buffer), Some(500));
+        assert_eq!(pop(&buffer), Some(250));
+        assert_eq!(pop(&buffer), None);
+
+        // back to buffering: one frame is not enough to play again
+        buffer.push(frame(1000));
+        assert_eq!(pop(&buffer), None);
+    }
+
+    #[test]
+    fn a_frame_is_skipped_when_the_depth_drifts_up() {
+        let buffer = JitterBuffer::new(JitterConfig::new(20, 200).unwrap());
+        for level in 0..10 {
+            buffer.push(frame(level));
+        }
+        // ten queued against a target of one: the first frame goes unheard
+        assert_eq!(pop(&buffer), Some(1));
+        assert_eq!(pop(&buffer), Some(3));
+
+        let buffer = JitterBuffer::new(JitterConfig::new(20, 200).unwrap());
+        buffer.push(frame(0));
+        buffer.push(frame(1));
+        assert_eq!(pop(&buffer), Some(0));
+        assert_eq!(pop(&buffer), Some(1));
+    }
+}
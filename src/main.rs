@@ -1,72 +1,168 @@
 use std::sync::Arc;
+use std::time::Duration;
 
-use audio::AudioServiceProvider;
+use audio::{AudioServiceProvider, BotUserCollector};
+use config::{Config, LogFormat};
 use poise::serenity_prelude as serenity;
 pub mod types;
 pub mod commands;
+pub mod config;
+pub mod api;
 pub mod audio;
+pub mod auth;
+pub mod jitter;
+pub mod metrics;
+pub mod mixer;
+pub mod passthrough;
+pub mod pcm;
+pub mod pool;
+pub mod record;
+pub mod routing;
+pub mod storage;
+pub mod supervisor;
+pub mod voice;
+pub mod watcher;
+#[cfg(test)]
+mod sim;
 /// Displays your or another user's account creation date
 use commands::*;
 use ::serenity::all::GatewayIntents;
 use songbird::{driver::DecodeMode, Songbird};
-use tokio::sync::mpsc;
+use pool::BotPool;
+use storage::Storage;
+use jitter::JitterConfig;
+use tokio::sync::{mpsc, Notify};
+use tracing_subscriber::EnvFilter;
 use types::Data;
+use voice::{SongbirdDriver, VoiceDriver};
+use watcher::VoiceStateWatcher;
 
 
 fn main() -> anyhow::Result<()> {
-    tracing_subscriber::fmt()
-        .json()
-        .init();
-    if let Err(e) = run() {
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            // logging is configured by the file being loaded, so report to stderr
+            eprintln!("Invalid configuration: {}", e);
+            std::process::exit(2);
+        }
+    };
+    // e.g. `VOISINC_LOG=info,voisinc::audio=debug`
+    let filter = EnvFilter::new(&config.log.level);
+    match config.log.format {
+        LogFormat::Json => tracing_subscriber::fmt()
+            .json()
+            .with_env_filter(filter)
+            .with_current_span(true)
+            .with_span_list(true)
+            .init(),
+        LogFormat::Pretty => tracing_subscriber::fmt()
+            .pretty()
+            .with_env_filter(filter)
+            .init(),
+    }
+    if let Err(e) = run(config) {
         tracing::error!("Failed to run bot: {}", e);
         std::process::exit(1);
     }
     Ok(())
 }
 #[tokio::main]
-async fn run() -> anyhow::Result<()> {
-    let val = std::env::var("DISCORD_TOKEN").expect("missing DISCORD_TOKEN");
-    let token: Vec<_> = val.split(";").collect();
+async fn run(config: Config) -> anyhow::Result<()> {
+    let token = &config.bots.tokens;
     let mut intents = serenity::GatewayIntents::all();
     intents.remove(GatewayIntents::GUILD_PRESENCES);
     intents.remove(GatewayIntents::GUILD_MEMBERS);
     intents.remove(GatewayIntents::MESSAGE_CONTENT);
     let songbird_config = songbird::Config::default()
         .decode_mode(DecodeMode::Decode);
-    let songbirds = (0..token.len()).map(|_| Songbird::serenity_from_config(songbird_config.clone())).collect();
-    let (tx, rx) = mpsc::channel(10);
+    let songbirds: Arc<[Arc<Songbird>]> = (0..token.len()).map(|_| Songbird::serenity_from_config(songbird_config.clone())).collect();
+    let (tx, rx) = mpsc::channel(config.audio.command_queue);
+    let storage = Arc::new(Storage::open(&config.state)?);
+    JitterConfig::set_default(config.audio.jitter);
     let volume_map = Default::default();
+    storage.load_volumes(&volume_map).await;
     let vm = Arc::clone(&volume_map);
+    let st = Arc::clone(&storage);
+    let routes = Default::default();
+    let rt = Arc::clone(&routes);
+    let bot_users: audio::BotUsers = Default::default();
+    let pool = Arc::new(BotPool::new(token.len()));
+    let pl = Arc::clone(&pool);
+    let pool_ready = Arc::new(Notify::new());
+    let replay_tx = tx.clone();
+    let passthrough = config.audio.opus_passthrough;
+    let guilds = config.commands.guilds.clone();
+    let control = Data::new(tx.clone(), Arc::clone(&volume_map), Arc::clone(&routes), Arc::clone(&storage), Arc::clone(&pool));
+    if let Some(addr) = config.listen.api {
+        let app = api::router(control.clone(), Some(&config.api.token));
+        tokio::spawn(async move {
+            if let Err(e) = api::serve(addr, app).await {
+                tracing::error!("control API failed: {}", e);
+            }
+        });
+    }
+    if let Some(path) = config.listen.socket.clone() {
+        let app = api::router(control, None);
+        tokio::spawn(async move {
+            if let Err(e) = api::serve_unix(&path, app).await {
+                tracing::error!("control socket failed: {}", e);
+            }
+        });
+    }
+    let watcher = Arc::new(VoiceStateWatcher::new(tx.clone(), Arc::clone(&storage), Arc::clone(&bot_users), Duration::from_secs(config.audio.idle_timeout_secs)));
     let framework = poise::Framework::builder()
         .options(poise::FrameworkOptions {
-            commands: vec![ping(), user_info()],
+            commands: vec![ping(), user_info(), join(), leave(), link(), unlink(), hears(), status(), jitter(), speakers(), broadcast(), volume(), bridge_role(), peer(), record()],
             ..Default::default()
         })
         .setup(move |ctx, _ready, framework| {
             Box::pin(async move {
-                poise::builtins::register_globally(ctx, &framework.options().commands).await?;
-                Ok(Data::new(tx, vm))
+                let commands = &framework.options().commands;
+                if guilds.is_empty() {
+                    poise::builtins::register_globally(ctx, commands).await?;
+                }
+                for gid in guilds {
+                    poise::builtins::register_in_guild(ctx, commands, gid).await?;
+                }
+                Ok(Data::new(tx, vm, rt, st, pl))
             })
         })
         .build();
     let mut clients = Vec::new();
-    let sb = Arc::clone(&songbirds);
+    let drivers = songbirds.iter().map(|s| Arc::new(SongbirdDriver(Arc::clone(s))) as Arc<dyn VoiceDriver>).collect();
     let songbird = Arc::clone(&songbirds[0]);
     let client = serenity::ClientBuilder::new(&token[0], intents)
         .framework(framework)
+        .event_handler(BotUserCollector::new(Arc::clone(&bot_users), token.len(), Arc::clone(&pool_ready)))
+        .event_handler(watcher.client(0))
+        .event_handler(pool.health(0))
         .voice_manager_arc(songbird)
         .await?;
-    let cache = Arc::clone(&client.cache);
-    let am = AudioServiceProvider::new(sb, rx, cache, volume_map);
+    let am = AudioServiceProvider::new(drivers, rx, volume_map, routes, Arc::clone(&bot_users), Arc::clone(&storage), Arc::clone(&pool), passthrough, config.record.dir.clone());
     clients.push(client);
     for i in 1..token.len() {
         let songbird = Arc::clone(&songbirds[i]);
         let client = serenity::ClientBuilder::new(&token[i], intents)
+            .event_handler(BotUserCollector::new(Arc::clone(&bot_users), token.len(), Arc::clone(&pool_ready)))
+            .event_handler(watcher.client(i))
+            .event_handler(pool.health(i))
             .voice_manager_arc(songbird)
             .await?;
         clients.push(client);
     }
-    let _ = am.run();
+    let _audio_service = am.run();
+    if let Some(addr) = config.listen.metrics {
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve(addr).await {
+                tracing::error!("metrics endpoint failed: {}", e);
+            }
+        });
+    }
+    tokio::spawn(async move {
+        pool_ready.notified().await;
+        storage.replay(&replay_tx).await;
+    });
     let handles: Vec<_> = clients.into_iter().map(|mut c| tokio::spawn(async move {c.start().await})).collect();
     futures::future::try_join_all(handles).await?
         .into_iter()
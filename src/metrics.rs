@@ -0,0 +1,185 @@
+use std::net::SocketAddr;
+use std::sync::LazyLock;
+
+use axum::http::header;
+use axum::routing::get;
+use axum::Router;
+use prometheus::{
+    HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+use serenity::model::id::{ChannelId, GuildId};
+
+/// Counters and gauges of the audio bridge, served on `/metrics`.
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub struct Metrics {
+    registry: Registry,
+    /// frames pushed into a destination mixer, by `guild`, `from` and `to`.
+    frames_forwarded: IntCounterVec,
+    /// frames a destination mixer dropped because it was full, by link.
+    lag_drops: IntCounterVec,
+    /// non-bot SSRCs heard on the last tick, by `guild` and `bot` index.
+    active_ssrcs: IntGaugeVec,
+    /// mix tracks playing, by `guild` and `bot` index.
+    tracks_playing: IntGaugeVec,
+    /// links in the routing graph, by `guild`.
+    links: IntGaugeVec,
+    /// audio commands handled, by `command` and `outcome`.
+    commands: IntCounterVec,
+    command_seconds: HistogramVec,
+    /// attempts to rejoin a failed call, by `guild` and `outcome`.
+    reconnects: IntCounterVec,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("voisinc".into()), None).unwrap();
+        let frames_forwarded = IntCounterVec::new(
+            Opts::new(
+                "frames_forwarded_total",
+                "Voice frames forwarded along a link",
+            ),
+            &["guild", "from", "to"],
+        )
+        .unwrap();
+        let lag_drops = IntCounterVec::new(
+            Opts::new("lag_drops_total", "Voice frames dropped by a lagging mixer"),
+            &["guild", "from", "to"],
+        )
+        .unwrap();
+        let active_ssrcs = IntGaugeVec::new(
+            Opts::new("active_ssrcs", "Speaking users heard by a bot"),
+            &["guild", "bot"],
+        )
+        .unwrap();
+        let tracks_playing = IntGaugeVec::new(
+            Opts::new("tracks_playing", "Mix tracks played by a bot"),
+            &["guild", "bot"],
+        )
+        .unwrap();
+        let links =
+            IntGaugeVec::new(Opts::new("links", "Links in the routing graph"), &["guild"]).unwrap();
+        let commands = IntCounterVec::new(
+            Opts::new("commands_total", "Audio commands handled"),
+            &["command", "outcome"],
+        )
+        .unwrap();
+        let command_seconds = HistogramVec::new(
+            HistogramOpts::new("command_seconds", "Time taken to handle an audio command"),
+            &["command"],
+        )
+        .unwrap();
+        let reconnects = IntCounterVec::new(
+            Opts::new("reconnects_total", "Attempts to rejoin a failed call"),
+            &["guild", "outcome"],
+        )
+        .unwrap();
+        registry
+            .register(Box::new(frames_forwarded.clone()))
+            .unwrap();
+        registry.register(Box::new(lag_drops.clone())).unwrap();
+        registry.register(Box::new(active_ssrcs.clone())).unwrap();
+        registry.register(Box::new(tracks_playing.clone())).unwrap();
+        registry.register(Box::new(links.clone())).unwrap();
+        registry.register(Box::new(commands.clone())).unwrap();
+        registry
+            .register(Box::new(command_seconds.clone()))
+            .unwrap();
+        registry.register(Box::new(reconnects.clone())).unwrap();
+        Self {
+            registry,
+            frames_forwarded,
+            lag_drops,
+            active_ssrcs,
+            tracks_playing,
+            links,
+            commands,
+            command_seconds,
+            reconnects,
+        }
+    }
+
+    /// `(frames_forwarded, lag_drops)` of the link from `from` into `to`.
+    pub fn link(&self, guild: GuildId, from: ChannelId, to: ChannelId) -> (IntCounter, IntCounter) {
+        let labels = [guild.to_string(), from.to_string(), to.to_string()];
+        let labels = labels.each_ref().map(String::as_str);
+        (
+            self.frames_forwarded.with_label_values(&labels),
+            self.lag_drops.with_label_values(&labels),
+        )
+    }
+
+    /// Stops exporting the link from `from` into `to`, once it is torn down.
+    pub fn forget_link(&self, guild: GuildId, from: ChannelId, to: ChannelId) {
+        let labels = [guild.to_string(), from.to_string(), to.to_string()];
+        let labels = labels.each_ref().map(String::as_str);
+        let _ = self.frames_forwarded.remove_label_values(&labels);
+        let _ = self.lag_drops.remove_label_values(&labels);
+    }
+
+    pub fn active_ssrcs(&self, guild: GuildId, bot: usize) -> IntGauge {
+        self.active_ssrcs
+            .with_label_values(&[&guild.to_string(), &bot.to_string()])
+    }
+
+    pub fn tracks_playing(&self, guild: GuildId, bot: usize) -> IntGauge {
+        self.tracks_playing
+            .with_label_values(&[&guild.to_string(), &bot.to_string()])
+    }
+
+    /// Stops exporting the gauges of bot `bot`'s call in `guild`, once it is
+    /// dropped.
+    pub fn forget_call(&self, guild: GuildId, bot: usize) {
+        let labels = [guild.to_string(), bot.to_string()];
+        let labels = labels.each_ref().map(String::as_str);
+        let _ = self.active_ssrcs.remove_label_values(&labels);
+        let _ = self.tracks_playing.remove_label_values(&labels);
+    }
+
+    pub fn set_links(&self, guild: GuildId, links: usize) {
+        self.links
+            .with_label_values(&[&guild.to_string()])
+            .set(links as i64);
+    }
+
+    /// Records one handled `command`, `outcome` being `ok` or the error kind.
+    pub fn command(&self, command: &str, outcome: &str, seconds: f64) {
+        self.commands.with_label_values(&[command, outcome]).inc();
+        self.command_seconds
+            .with_label_values(&[command])
+            .observe(seconds);
+    }
+
+    /// Records one attempt to rejoin a failed call: `recovered`, `failed`, or
+    /// `gave_up` after the last one.
+    pub fn reconnect(&self, guild: GuildId, outcome: &str) {
+        self.reconnects
+            .with_label_values(&[&guild.to_string(), outcome])
+            .inc();
+    }
+
+    /// Everything in the Prometheus text format.
+    pub fn encode(&self) -> String {
+        TextEncoder::new()
+            .encode_to_string(&self.registry.gather())
+            .unwrap_or_default()
+    }
+}
+
+/// Serves [`METRICS`] on `http://{addr}/metrics` until the listener fails.
+pub async fn serve(addr: SocketAddr) -> anyhow::Result<()> {
+    let app = Router::new().route(
+        "/metrics",
+        get(|| async {
+            (
+                [(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)],
+                METRICS.encode(),
+            )
+        }),
+    );
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    tracing::info!("serving metrics on {}", addr);
+    axum::serve(listener, app).await?;
+    Ok(())
+}
@@ -0,0 +1,236 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use dashmap::DashMap;
+use prometheus::IntCounter;
+use serenity::model::id::{ChannelId, GuildId};
+use songbird::input::core::io::MediaSource;
+use songbird::input::{Input, RawAdapter};
+
+use crate::jitter::{JitterBuffer, JitterConfig};
+use crate::metrics::METRICS;
+use crate::pcm::{i16_to_f32, PcmFormat};
+
+/// One tick of already summed, not yet clipped, voice laid out as
+/// [`PcmFormat::DISCORD_VOICE`] (interleaved stereo at 48 kHz).
+pub type Frame = Arc<[i32]>;
+
+/// One tick of a source's voice on its way to a destination call.
+#[derive(Debug, Clone)]
+pub struct Voice {
+    pub pcm: Frame,
+    /// the speaker's own Opus packet, when `pcm` is exactly that one packet
+    /// decoded at unity gain and can be passed through as is.
+    pub opus: Option<Arc<[u8]>>,
+}
+
+impl Voice {
+    pub fn pcm(pcm: Frame) -> Self {
+        Self { pcm, opus: None }
+    }
+}
+
+/// Sums the voice of every source routed into one destination call.
+///
+/// Each source pushes at most one frame per tick, so ten speakers in a source
+/// channel still make a single input here and a single track in the call.
+///
+/// Nothing here waits: every input is a [`JitterBuffer`] over a fixed size
+/// lock-free ring, and the reader plays silence when all of them are empty.
+/// Both sides only look an input up in the map and clone it out, so a push
+/// and a read never hold a map lock while touching a buffer.
+#[derive(Debug)]
+pub struct Mixer {
+    inputs: DashMap<ChannelId, Arc<MixerInput>>,
+    jitter: DashMap<ChannelId, JitterConfig>,
+    guild: GuildId,
+    /// the destination channel, for labelling metrics.
+    channel: ChannelId,
+    closed: AtomicBool,
+}
+
+#[derive(Debug)]
+struct MixerInput {
+    buffer: JitterBuffer,
+    forwarded: IntCounter,
+    lagged: IntCounter,
+}
+
+impl Mixer {
+    pub fn new(guild: GuildId, channel: ChannelId) -> Arc<Self> {
+        Arc::new(Self {
+            inputs: Default::default(),
+            jitter: Default::default(),
+            guild,
+            channel,
+            closed: AtomicBool::new(false),
+        })
+    }
+
+    /// Queues `voice` from `source`, dropping its oldest frame when full.
+    pub fn push(&self, source: ChannelId, voice: Voice) {
+        let input = match self.inputs.get(&source) {
+            Some(input) => Arc::clone(&input),
+            None => self.add_input(source),
+        };
+        if input.buffer.push(voice) {
+            input.lagged.inc();
+        }
+        input.forwarded.inc();
+    }
+
+    /// Starts the input of `source`, resolving its metrics before taking the
+    /// map's lock.
+    fn add_input(&self, source: ChannelId) -> Arc<MixerInput> {
+        let (forwarded, lagged) = METRICS.link(self.guild, source, self.channel);
+        let config = self.jitter.get(&source).map(|c| *c).unwrap_or_default();
+        let input = Arc::new(MixerInput {
+            buffer: JitterBuffer::new(config),
+            forwarded,
+            lagged,
+        });
+        // another push may have started it meanwhile
+        Arc::clone(&self.inputs.entry(source).or_insert(input))
+    }
+
+    pub fn remove_input(&self, source: ChannelId) {
+        if self.inputs.remove(&source).is_some() {
+            METRICS.forget_link(self.guild, source, self.channel);
+        }
+    }
+
+    /// Sets the jitter buffer of `source`, restarting it if already playing.
+    pub fn set_jitter(&self, source: ChannelId, config: JitterConfig) {
+        self.jitter.insert(source, config);
+        self.inputs.remove(&source);
+    }
+
+    /// Ends the stream; the track playing it stops on its next read.
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        for input in self.inputs.iter() {
+            METRICS.forget_link(self.guild, *input.key(), self.channel);
+        }
+    }
+
+    /// Pops one frame from every input and sums them, or `None` if all are silent.
+    ///
+    /// A lone voice is returned untouched, keeping its Opus packet.
+    pub fn next(&self) -> Option<Voice> {
+        let inputs: Vec<Arc<MixerInput>> = self.inputs.iter().map(|i| Arc::clone(&i)).collect();
+        let mut voices = inputs.iter().filter_map(|i| i.buffer.pop());
+        let first = voices.next()?;
+        let Some(second) = voices.next() else {
+            return Some(first);
+        };
+        let mut acc: Vec<i32> = Vec::new();
+        for voice in [first, second].into_iter().chain(voices) {
+            if acc.len() < voice.pcm.len() {
+                acc.resize(voice.pcm.len(), 0);
+            }
+            acc.iter_mut()
+                .zip(voice.pcm.iter())
+                .for_each(|(a, x)| *a += x);
+        }
+        Some(Voice::pcm(acc.into()))
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+}
+
+/// Level above which the limiter starts to compress.
+const KNEE: f32 = 0.8 * i16::MAX as f32;
+
+/// Soft limiter: linear up to [`KNEE`], then bends towards full scale so that
+/// loud sums are compressed instead of hard clipped.
+pub fn clip(sample: i32) -> i16 {
+    let x = sample as f32;
+    if x.abs() <= KNEE {
+        return x as i16;
+    }
+    let headroom = i16::MAX as f32 - KNEE;
+    let over = (x.abs() - KNEE) / headroom;
+    let y = KNEE + headroom * over.tanh();
+    (y.copysign(x)) as i16
+}
+
+/// The reading end of a [`Mixer`], played as a single songbird track.
+///
+/// Yields [`PcmFormat::SONGBIRD_RAW`] samples, which [`RawAdapter`] describes
+/// to songbird with a proper header. Reads never block: an underrun is filled
+/// with one frame of silence, so the track keeps songbird's 20 ms cadence.
+#[derive(Debug)]
+pub struct MixerRx {
+    mixer: Arc<Mixer>,
+    buf: Vec<u8>,
+    cur: usize,
+}
+
+impl MixerRx {
+    pub fn new(mixer: Arc<Mixer>) -> Self {
+        Self {
+            mixer,
+            buf: Vec::new(),
+            cur: 0,
+        }
+    }
+
+    pub fn into_input(self) -> Input {
+        let format = PcmFormat::SONGBIRD_RAW;
+        RawAdapter::new(self, format.sample_rate, format.channels as u32).into()
+    }
+
+    /// Takes the next mixed frame, or silence; `false` once the mixer is closed.
+    fn fill(&mut self) -> bool {
+        if self.mixer.is_closed() {
+            return false;
+        }
+        let samples: Vec<i16> = match self.mixer.next() {
+            Some(voice) => voice.pcm.iter().copied().map(clip).collect(),
+            None => vec![0; PcmFormat::DISCORD_VOICE.frame_samples()],
+        };
+        self.buf.clear();
+        self.buf.reserve(PcmFormat::SONGBIRD_RAW.frame_bytes());
+        self.buf.extend(
+            samples
+                .into_iter()
+                .flat_map(|t| i16_to_f32(t).to_le_bytes()),
+        );
+        self.cur = 0;
+        true
+    }
+}
+
+impl std::io::Read for MixerRx {
+    /// Reads at most to the end of the current frame, so songbird does not
+    /// buffer frames ahead of time.
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.cur == self.buf.len() && !self.fill() {
+            return Ok(0);
+        }
+        let count = buf.len().min(self.buf.len() - self.cur);
+        buf[..count].copy_from_slice(&self.buf[self.cur..self.cur + count]);
+        self.cur += count;
+        Ok(count)
+    }
+}
+
+impl std::io::Seek for MixerRx {
+    fn seek(&mut self, _: std::io::SeekFrom) -> std::io::Result<u64> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "not seekable audio",
+        ))
+    }
+}
+
+impl MediaSource for MixerRx {
+    fn is_seekable(&self) -> bool {
+        false
+    }
+    fn byte_len(&self) -> Option<u64> {
+        None
+    }
+}
@@ -0,0 +1,147 @@
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+use audiopus::coder::Encoder;
+use audiopus::{Application, Channels, SampleRate};
+use songbird::constants::SILENT_FRAME;
+use songbird::events::context_data::RtpData;
+use songbird::input::core::io::MediaSource;
+use songbird::input::core::probe::Hint;
+use songbird::input::{AudioStream, Input, LiveInput};
+use songbird::packet::rtp::RtpExtensionPacket;
+use songbird::packet::{Packet, PacketSize};
+
+use crate::mixer::{clip, Mixer, Voice};
+
+/// Largest Opus packet we write, per RFC 6716.
+pub(crate) const MAX_PACKET: usize = 1275;
+
+/// The Opus payload of a received RTP packet, with any header extension
+/// stripped, ready to be sent again as is.
+pub fn opus_payload(data: &RtpData) -> Option<Arc<[u8]>> {
+    let rtp = data.rtp();
+    let body = rtp
+        .payload()
+        .get(data.payload_offset..data.payload_end_pad)?;
+    let start = if rtp.get_extension() != 0 {
+        RtpExtensionPacket::new(body)?.packet_size()
+    } else {
+        0
+    };
+    Some(body.get(start..)?.into())
+}
+
+/// The reading end of a [`Mixer`] with a single source, played as Opus.
+///
+/// Songbird sends the packets of a lone Opus track at unity volume without
+/// decoding and re-encoding them. Ticks with a passable packet are forwarded
+/// as is; anything else (several speakers, a gain, concealment) is encoded
+/// here, and silence becomes the Opus silent frame.
+///
+/// The packets are framed as a DCA1 stream, which songbird reads natively.
+pub struct OpusRx {
+    mixer: Arc<Mixer>,
+    encoder: Mutex<Encoder>,
+    buf: Vec<u8>,
+    cur: usize,
+}
+
+impl OpusRx {
+    pub fn new(mixer: Arc<Mixer>) -> audiopus::Result<Self> {
+        let encoder = Encoder::new(SampleRate::Hz48000, Channels::Stereo, Application::Voip)?;
+        let mut rx = Self {
+            mixer,
+            encoder: Mutex::new(encoder),
+            buf: Vec::new(),
+            cur: 0,
+        };
+        rx.write_header();
+        Ok(rx)
+    }
+
+    pub fn into_input(self) -> Input {
+        let mut hint = Hint::new();
+        hint.with_extension("dca");
+        let stream = AudioStream {
+            input: Box::new(self) as Box<dyn MediaSource>,
+            hint: Some(hint),
+        };
+        Input::Live(LiveInput::Raw(stream), None)
+    }
+
+    fn write_header(&mut self) {
+        let meta = serde_json::json!({
+            "dca": { "version": 1, "tool": { "name": "voisinc", "version": env!("CARGO_PKG_VERSION") } },
+            "opus": { "mode": "voip", "sample_rate": 48000, "frame_size": 960, "vbr": true, "channels": 2 },
+        })
+        .to_string();
+        self.buf.extend_from_slice(b"DCA1");
+        self.buf
+            .extend_from_slice(&(meta.len() as u32).to_le_bytes());
+        self.buf.extend_from_slice(meta.as_bytes());
+    }
+
+    /// Frames the next packet; `false` once the mixer is closed.
+    fn fill(&mut self) -> bool {
+        if self.mixer.is_closed() {
+            return false;
+        }
+        let packet = match self.mixer.next() {
+            Some(Voice {
+                opus: Some(opus), ..
+            }) => opus,
+            Some(voice) => self.encode(&voice).unwrap_or_else(|| SILENT_FRAME.into()),
+            None => SILENT_FRAME.into(),
+        };
+        self.buf.clear();
+        self.buf
+            .extend_from_slice(&(packet.len() as u16).to_le_bytes());
+        self.buf.extend_from_slice(&packet);
+        self.cur = 0;
+        true
+    }
+
+    fn encode(&self, voice: &Voice) -> Option<Arc<[u8]>> {
+        let pcm: Vec<i16> = voice.pcm.iter().copied().map(clip).collect();
+        let mut out = [0; MAX_PACKET];
+        match self.encoder.lock().unwrap().encode(&pcm, &mut out) {
+            Ok(len) => Some(out[..len].into()),
+            Err(e) => {
+                tracing::warn!("failed to encode voice: {}", e);
+                None
+            }
+        }
+    }
+}
+
+impl std::io::Read for OpusRx {
+    /// Reads at most to the end of the current packet, see [`MixerRx`].
+    ///
+    /// [`MixerRx`]: crate::mixer::MixerRx
+    fn read(&mut self, mut buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.cur == self.buf.len() && !self.fill() {
+            return Ok(0);
+        }
+        let count = buf.write(&self.buf[self.cur..])?;
+        self.cur += count;
+        Ok(count)
+    }
+}
+
+impl std::io::Seek for OpusRx {
+    fn seek(&mut self, _: std::io::SeekFrom) -> std::io::Result<u64> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "not seekable audio",
+        ))
+    }
+}
+
+impl MediaSource for OpusRx {
+    fn is_seekable(&self) -> bool {
+        false
+    }
+    fn byte_len(&self) -> Option<u64> {
+        None
+    }
+}
@@ -0,0 +1,57 @@
+/// Layout of one PCM sample.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SampleType {
+    /// signed 16 bit, native endian.
+    I16,
+    /// 32 bit float in `-1.0..=1.0`, little endian.
+    F32,
+}
+
+impl SampleType {
+    pub const fn size(self) -> usize {
+        match self {
+            SampleType::I16 => 2,
+            SampleType::F32 => 4,
+        }
+    }
+}
+
+/// Explicit description of a PCM stream, so every stage of the pipeline
+/// agrees on what its bytes mean.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PcmFormat {
+    pub sample_rate: u32,
+    /// interleaved channel count, e.g. `L, R, L, R, ...` for 2.
+    pub channels: u16,
+    pub sample: SampleType,
+}
+
+impl PcmFormat {
+    /// What songbird decodes received voice into.
+    pub const DISCORD_VOICE: Self = Self {
+        sample_rate: 48_000,
+        channels: 2,
+        sample: SampleType::I16,
+    };
+
+    /// What the mixer feeds to songbird's raw reader.
+    pub const SONGBIRD_RAW: Self = Self {
+        sample_rate: 48_000,
+        channels: 2,
+        sample: SampleType::F32,
+    };
+
+    /// Interleaved samples in one 20 ms voice frame.
+    pub const fn frame_samples(&self) -> usize {
+        (self.sample_rate / 50) as usize * self.channels as usize
+    }
+
+    pub const fn frame_bytes(&self) -> usize {
+        self.frame_samples() * self.sample.size()
+    }
+}
+
+/// Converts a sample to the `-1.0..=1.0` range used by [`SampleType::F32`].
+pub fn i16_to_f32(sample: i16) -> f32 {
+    sample as f32 / -(i16::MIN as f32)
+}
@@ -0,0 +1,236 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use serde::Serialize;
+use serenity::all::{ConnectionStage, Context, Ready, ShardStageUpdateEvent};
+use serenity::async_trait;
+use serenity::model::id::{ChannelId, GuildId};
+
+pub type SharedBotPool = Arc<BotPool>;
+
+/// Which bot of the pool is in which channel, and whether it is connected.
+///
+/// A bot has at most one call per guild. Joining takes a slot before the
+/// voice connection is made, so joins running at once never pick the same bot.
+#[derive(Debug)]
+pub struct BotPool {
+    bots: Mutex<Vec<Bot>>,
+}
+
+#[derive(Debug, Default)]
+struct Bot {
+    /// channel of every guild the bot is in, or joining.
+    channels: HashMap<GuildId, ChannelId>,
+    /// whether its gateway connection is up.
+    healthy: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Allocation {
+    /// the bot already is in the channel.
+    Joined(usize),
+    /// the bot was free in the guild and now is assigned the channel.
+    Assigned(usize),
+}
+
+/// How many bots a guild can still bring into a channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Capacity {
+    pub free: usize,
+    /// free bots whose gateway connection is up.
+    pub healthy: usize,
+    pub total: usize,
+}
+
+/// One bot of the pool, for the control API.
+#[derive(Debug, Clone, Serialize)]
+pub struct BotSummary {
+    pub index: usize,
+    pub healthy: bool,
+    pub calls: Vec<Seat>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Seat {
+    pub guild: GuildId,
+    pub channel: ChannelId,
+}
+
+impl BotPool {
+    pub fn new(size: usize) -> Self {
+        Self {
+            bots: Mutex::new((0..size).map(|_| Bot::default()).collect()),
+        }
+    }
+
+    /// Picks the bot for `cid`.
+    ///
+    /// A bot already in the channel is reused rather than joining a second
+    /// one. Otherwise a bot free in the guild is assigned: connected ones
+    /// first, then the one in the fewest guilds. `None` if every bot already
+    /// is in another channel of the guild.
+    pub fn allocate(&self, gid: GuildId, cid: ChannelId) -> Option<Allocation> {
+        let mut bots = self.bots.lock().unwrap();
+        if let Some(idx) = bots.iter().position(|b| b.channels.get(&gid) == Some(&cid)) {
+            return Some(Allocation::Joined(idx));
+        }
+        let (idx, bot) = bots
+            .iter_mut()
+            .enumerate()
+            .filter(|(_, b)| !b.channels.contains_key(&gid))
+            .min_by_key(|(idx, b)| (!b.healthy, b.channels.len(), *idx))?;
+        bot.channels.insert(gid, cid);
+        Some(Allocation::Assigned(idx))
+    }
+
+    /// Frees bot `idx` in `gid`, if it still is assigned `cid`.
+    pub fn release(&self, idx: usize, gid: GuildId, cid: ChannelId) {
+        let mut bots = self.bots.lock().unwrap();
+        if let Some(bot) = bots.get_mut(idx) {
+            if bot.channels.get(&gid) == Some(&cid) {
+                bot.channels.remove(&gid);
+            }
+        }
+    }
+
+    /// The bot in `cid`, if any.
+    pub fn bot_in(&self, gid: GuildId, cid: ChannelId) -> Option<usize> {
+        self.bots
+            .lock()
+            .unwrap()
+            .iter()
+            .position(|b| b.channels.get(&gid) == Some(&cid))
+    }
+
+    pub fn capacity(&self, gid: GuildId) -> Capacity {
+        let bots = self.bots.lock().unwrap();
+        let free: Vec<_> = bots
+            .iter()
+            .filter(|b| !b.channels.contains_key(&gid))
+            .collect();
+        Capacity {
+            free: free.len(),
+            healthy: free.iter().filter(|b| b.healthy).count(),
+            total: bots.len(),
+        }
+    }
+
+    pub fn bots(&self) -> Vec<BotSummary> {
+        let bots = self.bots.lock().unwrap();
+        bots.iter()
+            .enumerate()
+            .map(|(index, b)| {
+                let mut calls: Vec<_> = b
+                    .channels
+                    .iter()
+                    .map(|(&guild, &channel)| Seat { guild, channel })
+                    .collect();
+                calls.sort_by_key(|s| s.guild);
+                BotSummary {
+                    index,
+                    healthy: b.healthy,
+                    calls,
+                }
+            })
+            .collect()
+    }
+
+    pub fn set_healthy(&self, idx: usize, healthy: bool) {
+        if let Some(bot) = self.bots.lock().unwrap().get_mut(idx) {
+            if bot.healthy != healthy {
+                tracing::info!(bot = idx, healthy, "bot health changed");
+            }
+            bot.healthy = healthy;
+        }
+    }
+
+    /// Event handler keeping the health of bot `idx` up to date.
+    pub fn health(self: &Arc<Self>, idx: usize) -> PoolHealth {
+        PoolHealth {
+            pool: Arc::clone(self),
+            idx,
+        }
+    }
+}
+
+/// Marks one bot healthy while its gateway connection is up.
+pub struct PoolHealth {
+    pool: SharedBotPool,
+    idx: usize,
+}
+
+#[async_trait]
+impl serenity::all::EventHandler for PoolHealth {
+    async fn ready(&self, _: Context, _: Ready) {
+        self.pool.set_healthy(self.idx, true);
+    }
+
+    async fn shard_stage_update(&self, _: Context, event: ShardStageUpdateEvent) {
+        self.pool
+            .set_healthy(self.idx, event.new == ConnectionStage::Connected);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GUILD: GuildId = GuildId::new(1);
+    const OTHER: GuildId = GuildId::new(2);
+    const A: ChannelId = ChannelId::new(10);
+    const B: ChannelId = ChannelId::new(11);
+    const C: ChannelId = ChannelId::new(12);
+
+    #[test]
+    fn prefers_healthy_then_least_busy_then_first() {
+        let pool = BotPool::new(3);
+        // all equal: the first
+        assert_eq!(pool.allocate(OTHER, A), Some(Allocation::Assigned(0)));
+        // bot 0 is in a guild already
+        assert_eq!(pool.allocate(GUILD, A), Some(Allocation::Assigned(1)));
+
+        let pool = BotPool::new(3);
+        pool.set_healthy(2, true);
+        pool.allocate(OTHER, A);
+        pool.allocate(GUILD, C);
+        // a connected bot beats idle ones, however busy it is
+        assert_eq!(pool.bot_in(OTHER, A), Some(2));
+        assert_eq!(pool.bot_in(GUILD, C), Some(2));
+        assert_eq!(pool.allocate(GUILD, A), Some(Allocation::Assigned(0)));
+    }
+
+    #[test]
+    fn a_bot_in_the_channel_is_reused() {
+        let pool = BotPool::new(2);
+        assert_eq!(pool.allocate(GUILD, A), Some(Allocation::Assigned(0)));
+        assert_eq!(pool.allocate(GUILD, A), Some(Allocation::Joined(0)));
+        assert_eq!(pool.allocate(GUILD, B), Some(Allocation::Assigned(1)));
+        assert_eq!(pool.allocate(GUILD, B), Some(Allocation::Joined(1)));
+    }
+
+    #[test]
+    fn a_full_guild_gets_no_bot() {
+        let pool = BotPool::new(2);
+        pool.set_healthy(1, true);
+        pool.allocate(GUILD, A);
+        assert_eq!(
+            pool.capacity(GUILD),
+            Capacity {
+                free: 1,
+                healthy: 0,
+                total: 2
+            }
+        );
+        pool.allocate(GUILD, B);
+        assert_eq!(pool.allocate(GUILD, C), None);
+        assert_eq!(pool.capacity(GUILD).free, 0);
+        // other guilds still have every bot
+        assert_eq!(pool.capacity(OTHER).free, 2);
+
+        // releasing a channel the bot has left since changes nothing
+        pool.release(0, GUILD, C);
+        assert_eq!(pool.allocate(GUILD, C), None);
+        pool.release(0, GUILD, B);
+        assert_eq!(pool.allocate(GUILD, C), Some(Allocation::Assigned(0)));
+    }
+}
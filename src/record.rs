@@ -0,0 +1,581 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread::JoinHandle;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use audiopus::coder::Encoder;
+use audiopus::{Application, Channels, SampleRate};
+use ogg::writing::{PacketWriteEndInfo, PacketWriter};
+use serde::Serialize;
+use serenity::model::id::{ChannelId, GuildId};
+use serenity::model::Timestamp;
+
+use crate::jitter::FRAME_MS;
+use crate::mixer::clip;
+use crate::passthrough::MAX_PACKET;
+use crate::pcm::PcmFormat;
+
+/// Name of the metadata sidecar written next to the audio files.
+const METADATA: &str = "recording.json";
+
+/// Samples per channel the Opus encoder primes with, skipped on playback.
+const PRE_SKIP: u16 = 312;
+
+/// How a recording is split into files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, poise::ChoiceParameter)]
+#[serde(rename_all = "snake_case")]
+pub enum Layout {
+    /// one file with everything heard in the channel.
+    #[name = "mixed"]
+    Mixed,
+    /// one file per speaker, all starting at their first word.
+    #[name = "per user"]
+    PerUser,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, poise::ChoiceParameter)]
+#[serde(rename_all = "snake_case")]
+pub enum Format {
+    #[name = "wav"]
+    Wav,
+    #[name = "ogg/opus"]
+    Opus,
+}
+
+impl Format {
+    fn extension(self) -> &'static str {
+        match self {
+            Format::Wav => "wav",
+            Format::Opus => "ogg",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct RecordOptions {
+    pub layout: Layout,
+    pub format: Format,
+}
+
+/// Whose voice a recorded frame is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(tag = "kind", content = "id", rename_all = "snake_case")]
+pub enum Speaker {
+    User(u64),
+    /// a bot of the pool, playing the voice of linked channels.
+    Bridge(u64),
+    /// an SSRC not mapped to a user yet.
+    Ssrc(u32),
+}
+
+impl Speaker {
+    fn file_stem(self) -> String {
+        match self {
+            Speaker::User(id) => format!("user-{}", id),
+            Speaker::Bridge(id) => format!("bridge-{}", id),
+            Speaker::Ssrc(ssrc) => format!("ssrc-{}", ssrc),
+        }
+    }
+}
+
+/// One tick of decoded voice, laid out as [`PcmFormat::DISCORD_VOICE`].
+pub type Tick = Vec<(Speaker, Vec<i16>)>;
+
+/// Records the voice heard in one channel into a directory of its own.
+///
+/// Ticks are handed to a writer thread, so encoding and disk writes never
+/// hold up the voice events. The recording ends when the recorder is
+/// finished or dropped, e.g. because the bot left; the thread then closes
+/// the files and writes the `recording.json` sidecar.
+#[derive(Debug)]
+pub struct Recorder {
+    ticks: Option<mpsc::Sender<Tick>>,
+    thread: Option<JoinHandle<io::Result<()>>>,
+    dir: PathBuf,
+    options: RecordOptions,
+}
+
+/// Creates a new directory `name` under `root`, suffixed with `-1`, `-2`...
+/// when recordings started within the same second, so none is overwritten.
+fn session_dir(root: &Path, name: &str) -> io::Result<PathBuf> {
+    std::fs::create_dir_all(root)?;
+    for n in 0.. {
+        let dir = match n {
+            0 => root.join(name),
+            n => root.join(format!("{}-{}", name, n)),
+        };
+        match std::fs::create_dir(&dir) {
+            Ok(()) => return Ok(dir),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    unreachable!()
+}
+
+impl Recorder {
+    /// Starts recording `channel` into a new directory under `root`.
+    pub fn start(
+        root: &Path,
+        guild: GuildId,
+        channel: ChannelId,
+        options: RecordOptions,
+    ) -> io::Result<Self> {
+        let started_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as i64);
+        let dir = session_dir(
+            root,
+            &format!("{}-{}-{}", guild, channel, started_ms / 1000),
+        )?;
+        let mut session = Session {
+            dir: dir.clone(),
+            guild,
+            channel,
+            options,
+            started_ms,
+            ticks: 0,
+            mix: None,
+            tracks: HashMap::new(),
+            participants: BTreeMap::new(),
+        };
+        if options.layout == Layout::Mixed {
+            session.mix = Some(Sink::create(&session.path("mix"), options.format)?);
+        }
+        let (tx, rx) = mpsc::channel();
+        let thread = std::thread::Builder::new()
+            .name("recorder".into())
+            .spawn(move || session.run(rx))?;
+        Ok(Self {
+            ticks: Some(tx),
+            thread: Some(thread),
+            dir,
+            options,
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn options(&self) -> RecordOptions {
+        self.options
+    }
+
+    /// Records one tick; called on every tick, silent ones included, so the
+    /// files keep time.
+    pub fn push(&self, tick: Tick) {
+        if let Some(ticks) = &self.ticks {
+            let _ = ticks.send(tick);
+        }
+    }
+
+    /// Ends the recording, giving the writer thread to wait for.
+    pub fn finish(mut self) -> JoinHandle<io::Result<()>> {
+        self.ticks.take();
+        self.thread.take().expect("recorder finished twice")
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        // the writer thread finishes on its own once the sender is gone
+        self.ticks.take();
+    }
+}
+
+/// The writer thread's side of a [`Recorder`].
+struct Session {
+    dir: PathBuf,
+    guild: GuildId,
+    channel: ChannelId,
+    options: RecordOptions,
+    /// unix time of the first tick, in ms.
+    started_ms: i64,
+    ticks: u64,
+    mix: Option<Sink>,
+    tracks: HashMap<Speaker, Sink>,
+    participants: BTreeMap<Speaker, Participant>,
+}
+
+#[derive(Debug, Serialize)]
+struct Participant {
+    #[serde(flatten)]
+    speaker: Speaker,
+    /// the speaker's own file, with the per user layout.
+    file: Option<String>,
+    #[serde(skip)]
+    last_tick: u64,
+    /// when the speaker was first heard, which is also where their file starts.
+    first_heard: Timestamp,
+    last_heard: Timestamp,
+    heard_ms: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct Metadata<'a> {
+    guild: GuildId,
+    channel: ChannelId,
+    layout: Layout,
+    format: Format,
+    started: Timestamp,
+    ended: Timestamp,
+    /// the mixed file, with the mixed layout.
+    mix: Option<String>,
+    participants: Vec<&'a Participant>,
+}
+
+impl Session {
+    fn run(mut self, ticks: mpsc::Receiver<Tick>) -> io::Result<()> {
+        let mut res = Ok(());
+        for tick in ticks {
+            if let Err(e) = self.record(tick) {
+                tracing::warn!(dir = %self.dir.display(), "recording failed: {}", e);
+                res = Err(e);
+                break;
+            }
+        }
+        let closed = self.close();
+        tracing::info!(dir = %self.dir.display(), ticks = self.ticks, "recording ended");
+        res.and(closed)
+    }
+
+    fn path(&self, stem: &str) -> PathBuf {
+        self.dir
+            .join(format!("{}.{}", stem, self.options.format.extension()))
+    }
+
+    /// Wall clock time of tick `n`.
+    fn time_of(&self, n: u64) -> Timestamp {
+        let ms = self.started_ms + (n * FRAME_MS as u64) as i64;
+        Timestamp::from_millis(ms).unwrap_or_else(|_| Timestamp::now())
+    }
+
+    fn record(&mut self, tick: Tick) -> io::Result<()> {
+        let n = self.ticks;
+        self.ticks += 1;
+        for (speaker, _) in &tick {
+            let time = self.time_of(n);
+            let p = self
+                .participants
+                .entry(*speaker)
+                .or_insert_with(|| Participant {
+                    speaker: *speaker,
+                    file: None,
+                    last_tick: n,
+                    first_heard: time,
+                    last_heard: time,
+                    heard_ms: 0,
+                });
+            p.last_tick = n;
+            p.heard_ms += FRAME_MS as u64;
+        }
+        if let Some(mix) = &mut self.mix {
+            let mut acc = vec![0i32; PcmFormat::DISCORD_VOICE.frame_samples()];
+            for (_, pcm) in &tick {
+                for (a, x) in acc.iter_mut().zip(pcm) {
+                    *a += *x as i32;
+                }
+            }
+            let frame: Vec<i16> = acc.into_iter().map(clip).collect();
+            return mix.write(&frame);
+        }
+        let silence = vec![0; PcmFormat::DISCORD_VOICE.frame_samples()];
+        let mut heard: HashMap<Speaker, Vec<i16>> = tick.into_iter().collect();
+        for (speaker, pcm) in heard.iter_mut() {
+            if !self.tracks.contains_key(speaker) {
+                let stem = speaker.file_stem();
+                let path = self.path(&stem);
+                self.tracks
+                    .insert(*speaker, Sink::create(&path, self.options.format)?);
+                if let Some(p) = self.participants.get_mut(speaker) {
+                    p.file = path.file_name().map(|f| f.to_string_lossy().into_owned());
+                }
+            }
+            pcm.resize(silence.len(), 0);
+        }
+        for (speaker, track) in self.tracks.iter_mut() {
+            track.write(heard.get(speaker).unwrap_or(&silence))?;
+        }
+        Ok(())
+    }
+
+    /// Closes every file and writes the sidecar.
+    fn close(&mut self) -> io::Result<()> {
+        let mut res = Ok(());
+        for sink in self
+            .mix
+            .take()
+            .into_iter()
+            .chain(self.tracks.drain().map(|(_, s)| s))
+        {
+            res = res.and(sink.finish());
+        }
+        let last_heard: Vec<_> = self
+            .participants
+            .values()
+            .map(|p| self.time_of(p.last_tick + 1))
+            .collect();
+        for (p, last) in self.participants.values_mut().zip(last_heard) {
+            p.last_heard = last;
+        }
+        let metadata = Metadata {
+            guild: self.guild,
+            channel: self.channel,
+            layout: self.options.layout,
+            format: self.options.format,
+            started: self.time_of(0),
+            ended: self.time_of(self.ticks),
+            mix: (self.options.layout == Layout::Mixed)
+                .then(|| format!("mix.{}", self.options.format.extension())),
+            participants: self.participants.values().collect(),
+        };
+        let data = serde_json::to_vec_pretty(&metadata)?;
+        res.and(std::fs::write(self.dir.join(METADATA), data))
+    }
+}
+
+/// One audio file being written.
+enum Sink {
+    Wav(hound::WavWriter<BufWriter<File>>),
+    Opus(OggOpus),
+}
+
+impl Sink {
+    fn create(path: &Path, format: Format) -> io::Result<Self> {
+        let voice = PcmFormat::DISCORD_VOICE;
+        Ok(match format {
+            Format::Wav => {
+                let spec = hound::WavSpec {
+                    channels: voice.channels,
+                    sample_rate: voice.sample_rate,
+                    bits_per_sample: 16,
+                    sample_format: hound::SampleFormat::Int,
+                };
+                Sink::Wav(hound::WavWriter::create(path, spec).map_err(io::Error::other)?)
+            }
+            Format::Opus => Sink::Opus(OggOpus::create(path)?),
+        })
+    }
+
+    /// Appends one frame.
+    fn write(&mut self, frame: &[i16]) -> io::Result<()> {
+        match self {
+            Sink::Wav(w) => {
+                let mut w = w.get_i16_writer(frame.len() as u32);
+                for &x in frame {
+                    w.write_sample(x);
+                }
+                w.flush().map_err(io::Error::other)
+            }
+            Sink::Opus(o) => o.write(frame),
+        }
+    }
+
+    fn finish(self) -> io::Result<()> {
+        match self {
+            Sink::Wav(w) => w.finalize().map_err(io::Error::other),
+            Sink::Opus(o) => o.finish(),
+        }
+    }
+}
+
+/// An Ogg/Opus file as laid out by RFC 7845: an `OpusHead` page, an
+/// `OpusTags` page, then one 20 ms packet per frame.
+struct OggOpus {
+    writer: PacketWriter<BufWriter<File>>,
+    encoder: Encoder,
+    serial: u32,
+    packets: u64,
+    /// the last packet, held back so it can end the stream.
+    pending: Option<Box<[u8]>>,
+}
+
+impl OggOpus {
+    fn create(path: &Path) -> io::Result<Self> {
+        let voice = PcmFormat::DISCORD_VOICE;
+        let encoder = Encoder::new(SampleRate::Hz48000, Channels::Stereo, Application::Audio)
+            .map_err(io::Error::other)?;
+        let mut writer = PacketWriter::new(BufWriter::new(File::create(path)?));
+        let serial = serial_of(path);
+        let mut head = b"OpusHead".to_vec();
+        head.push(1);
+        head.push(voice.channels as u8);
+        head.extend_from_slice(&PRE_SKIP.to_le_bytes());
+        head.extend_from_slice(&voice.sample_rate.to_le_bytes());
+        head.extend_from_slice(&0i16.to_le_bytes());
+        head.push(0);
+        writer.write_packet(head.into(), serial, PacketWriteEndInfo::EndPage, 0)?;
+        let vendor = concat!("voisinc ", env!("CARGO_PKG_VERSION"));
+        let mut tags = b"OpusTags".to_vec();
+        tags.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+        tags.extend_from_slice(vendor.as_bytes());
+        tags.extend_from_slice(&0u32.to_le_bytes());
+        writer.write_packet(tags.into(), serial, PacketWriteEndInfo::EndPage, 0)?;
+        Ok(Self {
+            writer,
+            encoder,
+            serial,
+            packets: 0,
+            pending: None,
+        })
+    }
+
+    fn write(&mut self, frame: &[i16]) -> io::Result<()> {
+        let mut out = [0; MAX_PACKET];
+        let len = self
+            .encoder
+            .encode(frame, &mut out)
+            .map_err(io::Error::other)?;
+        if let Some(packet) = self.pending.replace(out[..len].into()) {
+            self.flush(packet, PacketWriteEndInfo::NormalPacket)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self, packet: Box<[u8]>, end: PacketWriteEndInfo) -> io::Result<()> {
+        self.packets += 1;
+        // granule positions count samples per channel, priming included
+        let granule = self.packets * PcmFormat::DISCORD_VOICE.sample_rate as u64 / 50;
+        self.writer.write_packet(packet, self.serial, end, granule)
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        if let Some(packet) = self.pending.take() {
+            self.flush(packet, PacketWriteEndInfo::EndStream)?;
+        }
+        self.writer.inner_mut().flush()
+    }
+}
+
+/// The Ogg stream serial number of the file at `path`. It only has to be
+/// unique within the file, which holds a single stream.
+fn serial_of(path: &Path) -> u32 {
+    use std::hash::{Hash, Hasher};
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    path.hash(&mut hasher);
+    hasher.finish() as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+
+    const ALICE: Speaker = Speaker::User(100);
+    const BOB: Speaker = Speaker::User(101);
+
+    fn frame(level: i16) -> Vec<i16> {
+        vec![level; PcmFormat::DISCORD_VOICE.frame_samples()]
+    }
+
+    /// Records `ticks` into a fresh directory under `name`, giving the
+    /// session's directory.
+    fn record(name: &str, options: RecordOptions, ticks: Vec<Tick>) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("voisinc-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let recorder =
+            Recorder::start(&root, GuildId::new(1), ChannelId::new(10), options).unwrap();
+        let dir = recorder.dir().to_path_buf();
+        for tick in ticks {
+            recorder.push(tick);
+        }
+        recorder.finish().join().unwrap().unwrap();
+        dir
+    }
+
+    fn metadata(dir: &Path) -> Value {
+        serde_json::from_slice(&std::fs::read(dir.join(METADATA)).unwrap()).unwrap()
+    }
+
+    /// The packets of an Ogg file, checking the stream ends with the last.
+    fn packets(path: &Path) -> Vec<Vec<u8>> {
+        let mut reader = ogg::PacketReader::new(File::open(path).unwrap());
+        let mut packets = Vec::new();
+        let mut ended = false;
+        while let Some(packet) = reader.read_packet().unwrap() {
+            ended = packet.last_in_stream();
+            packets.push(packet.data);
+        }
+        assert!(ended);
+        packets
+    }
+
+    #[test]
+    fn mixed_wav_keeps_time() {
+        let options = RecordOptions {
+            layout: Layout::Mixed,
+            format: Format::Wav,
+        };
+        let ticks = vec![
+            vec![(ALICE, frame(1000)), (BOB, frame(500))],
+            vec![],
+            vec![(ALICE, frame(1000))],
+        ];
+        let dir = record("record-wav", options, ticks);
+
+        let mut wav = hound::WavReader::open(dir.join("mix.wav")).unwrap();
+        let spec = wav.spec();
+        assert_eq!(
+            (spec.channels, spec.sample_rate, spec.bits_per_sample),
+            (2, 48000, 16)
+        );
+        let samples: Vec<i16> = wav.samples().map(Result::unwrap).collect();
+        let len = PcmFormat::DISCORD_VOICE.frame_samples();
+        assert_eq!(samples.len(), 3 * len);
+        assert_eq!(
+            (samples[0], samples[len], samples[2 * len]),
+            (1500, 0, 1000)
+        );
+
+        let meta = metadata(&dir);
+        assert_eq!(meta["layout"], "mixed");
+        assert_eq!(meta["format"], "wav");
+        assert_eq!(meta["mix"], "mix.wav");
+        let participants = meta["participants"].as_array().unwrap();
+        assert_eq!(participants.len(), 2);
+        assert_eq!(participants[0]["kind"], "user");
+        assert_eq!(participants[0]["id"], 100);
+        assert_eq!(participants[0]["heard_ms"], 40);
+        assert_eq!(participants[1]["heard_ms"], 20);
+        std::fs::remove_dir_all(dir.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn per_user_opus_starts_at_the_first_word() {
+        let options = RecordOptions {
+            layout: Layout::PerUser,
+            format: Format::Opus,
+        };
+        let ticks = vec![
+            vec![(ALICE, frame(1000))],
+            vec![(ALICE, frame(1000)), (BOB, frame(500))],
+            vec![],
+        ];
+        let dir = record("record-ogg", options, ticks);
+
+        for (file, frames) in [("user-100.ogg", 3), ("user-101.ogg", 2)] {
+            let packets = packets(&dir.join(file));
+            assert!(packets[0].starts_with(b"OpusHead"), "{}", file);
+            assert!(packets[1].starts_with(b"OpusTags"), "{}", file);
+            assert_eq!(packets.len() - 2, frames, "{}", file);
+        }
+
+        let meta = metadata(&dir);
+        assert_eq!(meta["layout"], "per_user");
+        assert_eq!(meta["format"], "opus");
+        assert_eq!(meta["mix"], Value::Null);
+        let files: Vec<_> = meta["participants"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|p| p["file"].as_str().unwrap())
+            .collect();
+        assert_eq!(files, ["user-100.ogg", "user-101.ogg"]);
+        std::fs::remove_dir_all(dir.parent().unwrap()).unwrap();
+    }
+}
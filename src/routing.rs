@@ -0,0 +1,529 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use serenity::model::id::{ChannelId, GuildId};
+use serenity_voice_model::id::UserId;
+use thiserror::Error;
+use tokio::sync::Mutex;
+
+pub type SharedRoutingGraph = Arc<Mutex<RoutingGraph>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LinkMode {
+    /// voice of `from_id` is played in `to_id` only.
+    OneWay,
+    /// both channels hear each other; set up and torn down as one link.
+    Bidirectional,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Link {
+    pub from_id: ChannelId,
+    pub to_id: ChannelId,
+    pub mode: LinkMode,
+}
+
+impl Link {
+    pub fn new(from_id: ChannelId, to_id: ChannelId, mode: LinkMode) -> Self {
+        Self {
+            from_id,
+            to_id,
+            mode,
+        }
+    }
+    /// `(from, to)` pairs this link forwards voice along.
+    pub fn directions(&self) -> Vec<(ChannelId, ChannelId)> {
+        match self.mode {
+            LinkMode::OneWay => vec![(self.from_id, self.to_id)],
+            LinkMode::Bidirectional => {
+                vec![(self.from_id, self.to_id), (self.to_id, self.from_id)]
+            }
+        }
+    }
+    pub fn routes(&self, from_id: ChannelId, to_id: ChannelId) -> bool {
+        self.directions().contains(&(from_id, to_id))
+    }
+    pub fn joins(&self, a: ChannelId, b: ChannelId) -> bool {
+        (self.from_id, self.to_id) == (a, b) || (self.from_id, self.to_id) == (b, a)
+    }
+    pub fn touches(&self, cid: ChannelId) -> bool {
+        self.from_id == cid || self.to_id == cid
+    }
+}
+
+/// Which speakers of the source one direction of a link forwards.
+///
+/// Everyone is forwarded while both lists are empty. Users on `deny` never
+/// are, and once `allow` has anyone only they are. Voice whose speaker is
+/// not known yet is only forwarded by the empty filter, so a denied user is
+/// never heard before their SSRC is mapped.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SpeakerFilter {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allow: Vec<UserId>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deny: Vec<UserId>,
+}
+
+impl SpeakerFilter {
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+    /// Whether voice of `user`, `None` if unknown, is forwarded.
+    pub fn admits(&self, user: Option<UserId>) -> bool {
+        match user {
+            None => self.is_empty(),
+            Some(u) => {
+                !self.deny.contains(&u) && (self.allow.is_empty() || self.allow.contains(&u))
+            }
+        }
+    }
+    /// Adds `user` to the allow list, taking them off the deny list.
+    pub fn allow(&mut self, user: UserId) {
+        self.deny.retain(|u| *u != user);
+        if !self.allow.contains(&user) {
+            self.allow.push(user);
+        }
+    }
+    /// Adds `user` to the deny list, taking them off the allow list.
+    pub fn deny(&mut self, user: UserId) {
+        self.allow.retain(|u| *u != user);
+        if !self.deny.contains(&user) {
+            self.deny.push(user);
+        }
+    }
+    /// Takes `user` off both lists, `false` if they were on neither.
+    pub fn forget(&mut self, user: UserId) -> bool {
+        let len = self.allow.len() + self.deny.len();
+        self.allow.retain(|u| *u != user);
+        self.deny.retain(|u| *u != user);
+        self.allow.len() + self.deny.len() != len
+    }
+}
+
+/// A source channel played one way in every channel of a named group.
+///
+/// Listeners are receive-only: no link may take voice out of them, so
+/// nothing they say reaches the source or anybody else.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Broadcast {
+    pub name: String,
+    pub source: ChannelId,
+    #[serde(default)]
+    pub listeners: Vec<ChannelId>,
+}
+
+impl Broadcast {
+    pub fn new(name: impl Into<String>, source: ChannelId) -> Self {
+        Self {
+            name: name.into(),
+            source,
+            listeners: Vec::new(),
+        }
+    }
+    /// The link playing the source in each listener.
+    pub fn links(&self) -> Vec<Link> {
+        self.listeners
+            .iter()
+            .map(|&to| Link::new(self.source, to, LinkMode::OneWay))
+            .collect()
+    }
+    pub fn touches(&self, cid: ChannelId) -> bool {
+        self.source == cid || self.listeners.contains(&cid)
+    }
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum RoutingError {
+    #[error("Channel linked to itself")]
+    SelfLink,
+    #[error("Link would close a loop")]
+    Loop,
+    #[error("Channel listens to a broadcast")]
+    ReceiveOnly,
+    #[error("Channel already sends voice")]
+    Sends,
+    #[error("Link belongs to a broadcast")]
+    Broadcast,
+    #[error("Broadcast already exists")]
+    BroadcastExists,
+}
+
+/// All links of every guild.
+///
+/// The graph is the single source of truth for who hears whom; the audio
+/// side only mirrors its edges. Cycles are rejected on insert, the only
+/// allowed two-way edge pair being the one of a single bidirectional link.
+///
+/// A link between channels of two guilds is listed under both of them. As
+/// channel ids are unique across guilds, loops are looked for in all links
+/// at once.
+///
+/// [`Broadcast`] groups outlive their links: a channel that is suspended
+/// loses its links but stays in its groups, to be linked again on resume.
+#[derive(Debug, Default)]
+pub struct RoutingGraph {
+    guilds: HashMap<GuildId, Vec<Link>>,
+    broadcasts: HashMap<GuildId, Vec<Broadcast>>,
+}
+
+impl RoutingGraph {
+    pub fn links(&self, gid: GuildId) -> &[Link] {
+        self.guilds.get(&gid).map(Vec::as_slice).unwrap_or_default()
+    }
+
+    /// Inserts `link` between two channels of `gid`, see [`Self::insert_between`].
+    pub fn insert(&mut self, gid: GuildId, link: Link) -> Result<Option<Vec<Link>>, RoutingError> {
+        self.insert_between(gid, gid, link)
+    }
+
+    /// Inserts `link` from a channel of `from_gid` into one of `to_gid`,
+    /// returning the links it superseded.
+    ///
+    /// A bidirectional link replaces any link between the same two channels.
+    /// A one-way link that is already covered is not inserted and gives `None`.
+    pub fn insert_between(
+        &mut self,
+        from_gid: GuildId,
+        to_gid: GuildId,
+        link: Link,
+    ) -> Result<Option<Vec<Link>>, RoutingError> {
+        if link.from_id == link.to_id {
+            return Err(RoutingError::SelfLink);
+        }
+        if link
+            .directions()
+            .iter()
+            .any(|(from, _)| self.listens(*from))
+        {
+            return Err(RoutingError::ReceiveOnly);
+        }
+        if link.mode == LinkMode::OneWay
+            && self
+                .links(from_gid)
+                .iter()
+                .any(|l| l.routes(link.from_id, link.to_id))
+        {
+            return Ok(None);
+        }
+        let superseded =
+            |l: &Link| link.mode == LinkMode::Bidirectional && l.joins(link.from_id, link.to_id);
+        let edges: Vec<(ChannelId, ChannelId)> = self
+            .guilds
+            .values()
+            .flatten()
+            .filter(|l| !superseded(l))
+            .flat_map(|l| l.directions())
+            .collect();
+        for (from, to) in link.directions() {
+            if reaches(&edges, to, from) {
+                return Err(RoutingError::Loop);
+            }
+        }
+        let superseded = self.take(superseded);
+        self.guilds.entry(from_gid).or_default().push(link.clone());
+        if to_gid != from_gid {
+            self.guilds.entry(to_gid).or_default().push(link);
+        }
+        Ok(Some(superseded))
+    }
+
+    /// Removes the link of `gid` forwarding `from_id` into `to_id`.
+    pub fn remove(&mut self, gid: GuildId, from_id: ChannelId, to_id: ChannelId) -> Option<Link> {
+        let link = self
+            .links(gid)
+            .iter()
+            .find(|l| l.routes(from_id, to_id))?
+            .clone();
+        self.take(|l| *l == link).pop()
+    }
+
+    /// Removes every link that starts or ends at `cid`, a channel of `gid`.
+    pub fn remove_channel(&mut self, gid: GuildId, cid: ChannelId) -> Vec<Link> {
+        if !self.links(gid).iter().any(|l| l.touches(cid)) {
+            return Vec::new();
+        }
+        self.take(|l| l.touches(cid))
+    }
+
+    /// Removes the links matching `f` from every guild they are listed in.
+    fn take(&mut self, f: impl Fn(&Link) -> bool) -> Vec<Link> {
+        let mut removed: Vec<Link> = Vec::new();
+        for links in self.guilds.values_mut() {
+            let (gone, kept): (Vec<Link>, Vec<Link>) = links.drain(..).partition(|l| f(l));
+            *links = kept;
+            for link in gone {
+                if !removed.contains(&link) {
+                    removed.push(link);
+                }
+            }
+        }
+        removed
+    }
+
+    /// Channels whose voice is played in `cid`.
+    pub fn sources_of(&self, gid: GuildId, cid: ChannelId) -> Vec<ChannelId> {
+        self.edges(gid)
+            .filter(|(_, to)| *to == cid)
+            .map(|(from, _)| from)
+            .collect()
+    }
+
+    /// Channels `cid`'s voice is played in.
+    pub fn destinations_of(&self, gid: GuildId, cid: ChannelId) -> Vec<ChannelId> {
+        self.edges(gid)
+            .filter(|(from, _)| *from == cid)
+            .map(|(_, to)| to)
+            .collect()
+    }
+
+    pub fn broadcasts(&self, gid: GuildId) -> &[Broadcast] {
+        self.broadcasts
+            .get(&gid)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    pub fn broadcast(&self, gid: GuildId, name: &str) -> Option<&Broadcast> {
+        self.broadcasts(gid).iter().find(|b| b.name == name)
+    }
+
+    /// Starts the empty broadcast `name` of `source`.
+    pub fn start_broadcast(
+        &mut self,
+        gid: GuildId,
+        name: &str,
+        source: ChannelId,
+    ) -> Result<(), RoutingError> {
+        if self.broadcast(gid, name).is_some() {
+            return Err(RoutingError::BroadcastExists);
+        }
+        if self.listens(source) {
+            return Err(RoutingError::ReceiveOnly);
+        }
+        let broadcast = Broadcast::new(name, source);
+        self.broadcasts.entry(gid).or_default().push(broadcast);
+        Ok(())
+    }
+
+    /// Removes broadcast `name`; its links are left to the caller.
+    pub fn end_broadcast(&mut self, gid: GuildId, name: &str) -> Option<Broadcast> {
+        let broadcasts = self.broadcasts.get_mut(&gid)?;
+        let i = broadcasts.iter().position(|b| b.name == name)?;
+        Some(broadcasts.remove(i))
+    }
+
+    /// Whether `cid` may listen to a broadcast of `source`: it must not send
+    /// voice anywhere, by a link or as the source of a broadcast.
+    pub fn can_listen(&self, source: ChannelId, cid: ChannelId) -> Result<(), RoutingError> {
+        if cid == source {
+            return Err(RoutingError::SelfLink);
+        }
+        let sends = self
+            .guilds
+            .values()
+            .flatten()
+            .flat_map(|l| l.directions())
+            .any(|(from, _)| from == cid);
+        let sources = self.broadcasts.values().flatten().any(|b| b.source == cid);
+        if sends || sources {
+            return Err(RoutingError::Sends);
+        }
+        Ok(())
+    }
+
+    /// Adds `cid` to the listeners of broadcast `name`, `None` if there is no
+    /// such broadcast. See [`Self::can_listen`].
+    pub fn add_listener(
+        &mut self,
+        gid: GuildId,
+        name: &str,
+        cid: ChannelId,
+    ) -> Option<Result<(), RoutingError>> {
+        let source = self.broadcast(gid, name)?.source;
+        if let Err(e) = self.can_listen(source, cid) {
+            return Some(Err(e));
+        }
+        let broadcast = self.broadcast_mut(gid, name)?;
+        if !broadcast.listeners.contains(&cid) {
+            broadcast.listeners.push(cid);
+        }
+        Some(Ok(()))
+    }
+
+    /// Takes `cid` out of broadcast `name`, `false` if it was not listening.
+    pub fn remove_listener(&mut self, gid: GuildId, name: &str, cid: ChannelId) -> bool {
+        let Some(broadcast) = self.broadcast_mut(gid, name) else {
+            return false;
+        };
+        let len = broadcast.listeners.len();
+        broadcast.listeners.retain(|l| *l != cid);
+        broadcast.listeners.len() != len
+    }
+
+    /// Takes `cid` out of every broadcast of `gid`, ending and returning
+    /// those it is the source of.
+    pub fn remove_from_broadcasts(&mut self, gid: GuildId, cid: ChannelId) -> Vec<Broadcast> {
+        let Some(broadcasts) = self.broadcasts.get_mut(&gid) else {
+            return Vec::new();
+        };
+        let (ended, kept) = broadcasts.drain(..).partition(|b| b.source == cid);
+        *broadcasts = kept;
+        for b in broadcasts {
+            b.listeners.retain(|l| *l != cid);
+        }
+        ended
+    }
+
+    /// Whether the link from `from_id` into `to_id` is one of a broadcast.
+    pub fn in_broadcast(&self, gid: GuildId, from_id: ChannelId, to_id: ChannelId) -> bool {
+        self.broadcasts(gid)
+            .iter()
+            .any(|b| b.source == from_id && b.listeners.contains(&to_id))
+    }
+
+    fn broadcast_mut(&mut self, gid: GuildId, name: &str) -> Option<&mut Broadcast> {
+        self.broadcasts
+            .get_mut(&gid)?
+            .iter_mut()
+            .find(|b| b.name == name)
+    }
+
+    /// Whether `cid` listens to a broadcast of any guild.
+    fn listens(&self, cid: ChannelId) -> bool {
+        self.broadcasts
+            .values()
+            .flatten()
+            .any(|b| b.listeners.contains(&cid))
+    }
+
+    fn edges(&self, gid: GuildId) -> impl Iterator<Item = (ChannelId, ChannelId)> + '_ {
+        self.links(gid).iter().flat_map(|l| l.directions())
+    }
+}
+
+/// Whether `to` can be reached from `from` along `edges`.
+fn reaches(edges: &[(ChannelId, ChannelId)], from: ChannelId, to: ChannelId) -> bool {
+    let mut seen = HashSet::new();
+    let mut stack = vec![from];
+    while let Some(cur) = stack.pop() {
+        if cur == to {
+            return true;
+        }
+        if seen.insert(cur) {
+            stack.extend(edges.iter().filter(|(f, _)| *f == cur).map(|(_, t)| *t));
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GUILD: GuildId = GuildId::new(1);
+    const PEER: GuildId = GuildId::new(2);
+    const A: ChannelId = ChannelId::new(10);
+    const B: ChannelId = ChannelId::new(11);
+    const C: ChannelId = ChannelId::new(12);
+
+    fn one_way(from_id: ChannelId, to_id: ChannelId) -> Link {
+        Link::new(from_id, to_id, LinkMode::OneWay)
+    }
+
+    #[test]
+    fn rejects_self_links_and_loops() {
+        let mut graph = RoutingGraph::default();
+        assert_eq!(
+            graph.insert(GUILD, one_way(A, A)),
+            Err(RoutingError::SelfLink)
+        );
+        graph.insert(GUILD, one_way(A, B)).unwrap();
+        assert_eq!(graph.insert(GUILD, one_way(B, A)), Err(RoutingError::Loop));
+        graph.insert(GUILD, one_way(B, C)).unwrap();
+        assert_eq!(graph.insert(GUILD, one_way(C, A)), Err(RoutingError::Loop));
+        assert_eq!(graph.links(GUILD), [one_way(A, B), one_way(B, C)]);
+    }
+
+    #[test]
+    fn bidirectional_replaces_and_covers_one_way() {
+        let mut graph = RoutingGraph::default();
+        graph.insert(GUILD, one_way(A, B)).unwrap();
+        let both = Link::new(B, A, LinkMode::Bidirectional);
+        assert_eq!(
+            graph.insert(GUILD, both.clone()),
+            Ok(Some(vec![one_way(A, B)]))
+        );
+        assert_eq!(graph.links(GUILD), std::slice::from_ref(&both));
+
+        // either direction already is covered
+        assert_eq!(graph.insert(GUILD, one_way(A, B)), Ok(None));
+        assert_eq!(graph.insert(GUILD, one_way(B, A)), Ok(None));
+        assert_eq!(graph.sources_of(GUILD, A), [B]);
+        assert_eq!(graph.destinations_of(GUILD, A), [B]);
+
+        // a bidirectional link still can't close a longer loop
+        graph.insert(GUILD, one_way(B, C)).unwrap();
+        let res = graph.insert(GUILD, Link::new(C, A, LinkMode::Bidirectional));
+        assert_eq!(res, Err(RoutingError::Loop));
+
+        assert_eq!(graph.remove(GUILD, A, B), Some(both));
+        assert_eq!(graph.links(GUILD), [one_way(B, C)]);
+    }
+
+    #[test]
+    fn links_across_guilds_are_listed_under_both() {
+        let mut graph = RoutingGraph::default();
+        graph.insert_between(GUILD, PEER, one_way(A, B)).unwrap();
+        graph.insert_between(PEER, GUILD, one_way(C, A)).unwrap();
+        assert_eq!(graph.links(GUILD), [one_way(A, B), one_way(C, A)]);
+        assert_eq!(graph.links(PEER), [one_way(A, B), one_way(C, A)]);
+        // loops are looked for across guilds
+        let res = graph.insert_between(PEER, GUILD, one_way(B, C));
+        assert_eq!(res, Err(RoutingError::Loop));
+
+        assert_eq!(graph.remove(PEER, A, B), Some(one_way(A, B)));
+        assert_eq!(graph.links(GUILD), [one_way(C, A)]);
+        assert_eq!(graph.links(PEER), [one_way(C, A)]);
+
+        assert_eq!(graph.remove_channel(GUILD, A), [one_way(C, A)]);
+        assert!(graph.links(GUILD).is_empty());
+        assert!(graph.links(PEER).is_empty());
+    }
+
+    #[test]
+    fn broadcast_listeners_are_receive_only() {
+        let mut graph = RoutingGraph::default();
+        graph.start_broadcast(GUILD, "stage", A).unwrap();
+        let res = graph.start_broadcast(GUILD, "stage", B);
+        assert_eq!(res, Err(RoutingError::BroadcastExists));
+        graph.add_listener(GUILD, "stage", B).unwrap().unwrap();
+        graph.insert(GUILD, one_way(A, B)).unwrap();
+        assert!(graph.in_broadcast(GUILD, A, B));
+
+        assert_eq!(
+            graph.insert(GUILD, one_way(B, C)),
+            Err(RoutingError::ReceiveOnly)
+        );
+        let res = graph.insert(GUILD, Link::new(A, B, LinkMode::Bidirectional));
+        assert_eq!(res, Err(RoutingError::ReceiveOnly));
+        // the source can't listen, nor can a channel that sends elsewhere
+        assert_eq!(
+            graph.add_listener(GUILD, "stage", A),
+            Some(Err(RoutingError::SelfLink))
+        );
+        graph.insert(GUILD, one_way(C, A)).unwrap();
+        assert_eq!(
+            graph.add_listener(GUILD, "stage", C),
+            Some(Err(RoutingError::Sends))
+        );
+        assert_eq!(graph.add_listener(GUILD, "other", C), None);
+
+        let ended = graph.remove_from_broadcasts(GUILD, A);
+        assert_eq!(ended.len(), 1);
+        assert!(graph.broadcasts(GUILD).is_empty());
+        assert!(!graph.in_broadcast(GUILD, A, B));
+    }
+}
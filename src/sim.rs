@@ -0,0 +1,390 @@
+//! An in-process stand-in for Discord voice, to test the bridge without a
+//! gateway.
+//!
+//! Every bot of the pool gets a [`VoiceDriver`] whose calls live in a shared
+//! [`Sim`]. Tests make users speak into channels and step time one 20 ms
+//! [`Sim::tick`] at a time: each tick hands the voice queued for a channel to
+//! the calls there as a [`VoiceEvent::Tick`], then reads one frame from every
+//! track playing in every call.
+
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+
+use serenity::async_trait;
+use serenity::model::id::{ChannelId, GuildId};
+use serenity_voice_model::id::UserId;
+
+use crate::audio::VoiceEventHandler;
+use crate::pcm::PcmFormat;
+use crate::storage::Storage;
+use crate::voice::{
+    ChannelLookup, Heard, TrackSource, VoiceCall, VoiceDriver, VoiceEvent, VoiceMember, VoiceTrack,
+};
+
+/// A frame a call played on a tick.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Played {
+    /// samples laid out as [`PcmFormat::DISCORD_VOICE`].
+    Pcm(Vec<i16>),
+    /// an Opus packet, sent as is.
+    Opus(Vec<u8>),
+}
+
+#[derive(Clone, Default)]
+pub struct Sim {
+    state: Arc<State>,
+}
+
+#[derive(Default)]
+struct State {
+    /// calls by bot and guild.
+    calls: Mutex<HashMap<(usize, GuildId), Arc<Call>>>,
+    members: Mutex<HashMap<(GuildId, ChannelId), Vec<VoiceMember>>>,
+    /// voice spoken in each channel since the last tick.
+    spoken: Mutex<HashMap<ChannelId, Vec<Spoken>>>,
+    /// joins still to fail, see [`Sim::fail_joins`].
+    failing_joins: AtomicUsize,
+}
+
+/// Numbers tracks for their [`VoiceTrack::id`].
+static TRACK_IDS: AtomicUsize = AtomicUsize::new(0);
+
+struct Spoken {
+    ssrc: u32,
+    pcm: Vec<i16>,
+    opus: Option<Arc<[u8]>>,
+}
+
+struct Call {
+    channel: ChannelId,
+    handlers: Mutex<Vec<VoiceEventHandler>>,
+    tracks: Mutex<Vec<Track>>,
+    played: Mutex<Vec<Played>>,
+}
+
+struct Track {
+    source: Source,
+    stopped: Arc<AtomicBool>,
+}
+
+enum Source {
+    Pcm(crate::mixer::MixerRx),
+    /// whether the DCA header was read yet.
+    Opus(crate::passthrough::OpusRx, bool),
+}
+
+impl Sim {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The voice driver of bot `bot`.
+    pub fn driver(&self, bot: usize) -> Arc<dyn VoiceDriver> {
+        Arc::new(Driver {
+            bot,
+            state: Arc::clone(&self.state),
+        })
+    }
+
+    /// Puts `user` in `cid`, speaking as `ssrc`.
+    pub async fn enter(&self, gid: GuildId, cid: ChannelId, user: UserId, ssrc: u32) {
+        self.state
+            .members
+            .lock()
+            .unwrap()
+            .entry((gid, cid))
+            .or_default()
+            .push(VoiceMember { user, bot: false });
+        for handler in self.handlers_in(cid) {
+            handler.event(VoiceEvent::Speaking { ssrc, user }).await;
+        }
+    }
+
+    /// Queues a frame of `ssrc`'s voice in `cid` for the next tick.
+    pub fn say(&self, cid: ChannelId, ssrc: u32, pcm: Vec<i16>, opus: Option<&[u8]>) {
+        self.state
+            .spoken
+            .lock()
+            .unwrap()
+            .entry(cid)
+            .or_default()
+            .push(Spoken {
+                ssrc,
+                pcm,
+                opus: opus.map(Into::into),
+            });
+    }
+
+    /// Makes the next `n` joins of any bot fail.
+    pub fn fail_joins(&self, n: usize) {
+        self.state.failing_joins.store(n, Ordering::Relaxed);
+    }
+
+    /// Fails the voice connection of the call in `cid`, as a lost voice
+    /// server would.
+    pub async fn disconnect(&self, cid: ChannelId) {
+        for handler in self.handlers_in(cid) {
+            let event = VoiceEvent::DriverDisconnect {
+                kind: "runtime".into(),
+                reason: Some("connection lost".into()),
+            };
+            handler.event(event).await;
+        }
+    }
+
+    /// Delivers the voice spoken since the last tick, then plays a frame of
+    /// every track.
+    pub async fn tick(&self) {
+        let spoken = std::mem::take(&mut *self.state.spoken.lock().unwrap());
+        for call in self.calls() {
+            let handlers = call.handlers.lock().unwrap().clone();
+            let voices = spoken.get(&call.channel).map_or(&[][..], Vec::as_slice);
+            for handler in handlers {
+                let heard = voices
+                    .iter()
+                    .map(|s| Heard {
+                        ssrc: s.ssrc,
+                        pcm: Some(&s.pcm),
+                        opus: s.opus.clone(),
+                    })
+                    .collect();
+                handler.event(VoiceEvent::Tick(heard)).await;
+            }
+        }
+        for call in self.calls() {
+            call.play();
+        }
+    }
+
+    /// Which bot has a call in `cid`.
+    pub fn bot_in(&self, cid: ChannelId) -> Option<usize> {
+        let calls = self.state.calls.lock().unwrap();
+        calls
+            .iter()
+            .find(|(_, c)| c.channel == cid)
+            .map(|((bot, _), _)| *bot)
+    }
+
+    /// Tracks playing in `cid`.
+    pub fn tracks(&self, cid: ChannelId) -> usize {
+        self.call_in(cid)
+            .map_or(0, |c| c.tracks.lock().unwrap().len())
+    }
+
+    /// Frames played in `cid` since the last call.
+    pub fn played(&self, cid: ChannelId) -> Vec<Played> {
+        self.call_in(cid)
+            .map(|c| std::mem::take(&mut *c.played.lock().unwrap()))
+            .unwrap_or_default()
+    }
+
+    fn calls(&self) -> Vec<Arc<Call>> {
+        self.state.calls.lock().unwrap().values().cloned().collect()
+    }
+
+    fn call_in(&self, cid: ChannelId) -> Option<Arc<Call>> {
+        self.calls().into_iter().find(|c| c.channel == cid)
+    }
+
+    fn handlers_in(&self, cid: ChannelId) -> Vec<VoiceEventHandler> {
+        self.call_in(cid)
+            .map(|c| c.handlers.lock().unwrap().clone())
+            .unwrap_or_default()
+    }
+}
+
+impl ChannelLookup for Sim {
+    fn voice_members(&self, gid: GuildId, cid: ChannelId) -> Option<Vec<VoiceMember>> {
+        let members = self.state.members.lock().unwrap();
+        let mut found = members.get(&(gid, cid)).cloned().unwrap_or_default();
+        let calls = self.state.calls.lock().unwrap();
+        for ((bot, _), _) in calls.iter().filter(|(_, c)| c.channel == cid) {
+            found.push(VoiceMember {
+                user: bot_user(*bot),
+                bot: true,
+            });
+        }
+        Some(found)
+    }
+}
+
+/// A state file in the temp directory, removed on drop, for tests that
+/// need a [`Storage`].
+pub struct TempState(PathBuf);
+
+impl TempState {
+    /// Starts `voisinc-{name}-{pid}.json` afresh; `name` must be unique
+    /// across the tests.
+    pub fn new(name: &str) -> Self {
+        let path =
+            std::env::temp_dir().join(format!("voisinc-{}-{}.json", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        Self(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    /// Opens the state, as saved so far.
+    pub fn open(&self) -> Arc<Storage> {
+        Arc::new(Storage::open(&self.0).unwrap())
+    }
+}
+
+impl Drop for TempState {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// The user id bot `bot` is simulated as.
+pub fn bot_user(bot: usize) -> UserId {
+    UserId(9000 + bot as u64)
+}
+
+impl Call {
+    /// Reads a frame from every track, dropping the stopped and ended ones.
+    fn play(&self) {
+        let mut tracks = self.tracks.lock().unwrap();
+        let mut played = self.played.lock().unwrap();
+        tracks.retain_mut(|track| {
+            if track.stopped.load(Ordering::Acquire) {
+                return false;
+            }
+            match track.source.read_frame() {
+                Some(frame) => {
+                    played.push(frame);
+                    true
+                }
+                None => false,
+            }
+        });
+    }
+}
+
+impl Source {
+    /// The next frame, `None` once the stream ended.
+    fn read_frame(&mut self) -> Option<Played> {
+        match self {
+            Source::Pcm(rx) => {
+                let mut buf = vec![0; PcmFormat::SONGBIRD_RAW.frame_bytes()];
+                rx.read_exact(&mut buf).ok()?;
+                let pcm = buf
+                    .chunks_exact(4)
+                    .map(|b| {
+                        let x = f32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+                        (x * -(i16::MIN as f32)).round() as i16
+                    })
+                    .collect();
+                Some(Played::Pcm(pcm))
+            }
+            Source::Opus(rx, header) => {
+                if !*header {
+                    let mut head = [0; 8];
+                    rx.read_exact(&mut head).ok()?;
+                    assert_eq!(&head[..4], b"DCA1");
+                    let len = u32::from_le_bytes([head[4], head[5], head[6], head[7]]);
+                    rx.read_exact(&mut vec![0; len as usize]).ok()?;
+                    *header = true;
+                }
+                let mut len = [0; 2];
+                rx.read_exact(&mut len).ok()?;
+                let mut packet = vec![0; u16::from_le_bytes(len) as usize];
+                rx.read_exact(&mut packet).ok()?;
+                Some(Played::Opus(packet))
+            }
+        }
+    }
+}
+
+struct Driver {
+    bot: usize,
+    state: Arc<State>,
+}
+
+#[async_trait]
+impl VoiceDriver for Driver {
+    async fn join(&self, gid: GuildId, cid: ChannelId) -> anyhow::Result<Arc<dyn VoiceCall>> {
+        let failing = &self.state.failing_joins;
+        if failing
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+            .is_ok()
+        {
+            return Err(anyhow::anyhow!("voice server unreachable"));
+        }
+        let call = Arc::new(Call {
+            channel: cid,
+            handlers: Default::default(),
+            tracks: Default::default(),
+            played: Default::default(),
+        });
+        let handle = CallHandle(Arc::downgrade(&call));
+        self.state
+            .calls
+            .lock()
+            .unwrap()
+            .insert((self.bot, gid), call);
+        Ok(Arc::new(handle))
+    }
+
+    async fn leave(&self, gid: GuildId) -> anyhow::Result<()> {
+        match self.state.calls.lock().unwrap().remove(&(self.bot, gid)) {
+            Some(_) => Ok(()),
+            None => Err(anyhow::anyhow!("not in a call")),
+        }
+    }
+}
+
+/// Like songbird's, the sim owns its calls and hands out weak handles.
+#[derive(Debug)]
+struct CallHandle(Weak<Call>);
+
+#[async_trait]
+impl VoiceCall for CallHandle {
+    async fn subscribe(&self, handler: VoiceEventHandler) {
+        if let Some(call) = self.0.upgrade() {
+            call.handlers.lock().unwrap().push(handler);
+        }
+    }
+
+    async fn unsubscribe(&self) {
+        if let Some(call) = self.0.upgrade() {
+            call.handlers.lock().unwrap().clear();
+        }
+    }
+
+    async fn play(&self, source: TrackSource) -> Option<Box<dyn VoiceTrack>> {
+        let call = self.0.upgrade()?;
+        let source = match source {
+            TrackSource::Pcm(rx) => Source::Pcm(rx),
+            TrackSource::Opus(rx) => Source::Opus(rx, false),
+        };
+        let stopped = Arc::new(AtomicBool::new(false));
+        call.tracks.lock().unwrap().push(Track {
+            source,
+            stopped: Arc::clone(&stopped),
+        });
+        let id = TRACK_IDS.fetch_add(1, Ordering::Relaxed);
+        Some(Box::new(TrackHandle { id, stopped }))
+    }
+}
+
+#[derive(Debug)]
+struct TrackHandle {
+    id: usize,
+    stopped: Arc<AtomicBool>,
+}
+
+impl VoiceTrack for TrackHandle {
+    fn id(&self) -> String {
+        self.id.to_string()
+    }
+
+    fn stop(&self) {
+        self.stopped.store(true, Ordering::Release);
+    }
+}
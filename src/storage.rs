@@ -0,0 +1,564 @@
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+use serenity::model::id::{ChannelId, GuildId, RoleId};
+use serenity_voice_model::id::UserId;
+use tokio::sync::{mpsc, Mutex};
+
+use crate::audio::{request, AudioCommand, AudioCommandPayload, Gain, GlobalVolumeMap};
+use crate::jitter::JitterConfig;
+use crate::routing::{Broadcast, Link, LinkMode, SpeakerFilter};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SavedJoin {
+    pub guild: GuildId,
+    pub channel: ChannelId,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SavedLink {
+    pub guild: GuildId,
+    pub from: ChannelId,
+    /// guild of `to`, when it is not `guild`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to_guild: Option<GuildId>,
+    pub to: ChannelId,
+    #[serde(default)]
+    pub bidirectional: bool,
+}
+
+impl SavedLink {
+    pub fn mode(&self) -> LinkMode {
+        if self.bidirectional {
+            LinkMode::Bidirectional
+        } else {
+            LinkMode::OneWay
+        }
+    }
+    pub fn to_guild(&self) -> GuildId {
+        self.to_guild.unwrap_or(self.guild)
+    }
+    /// Whether either end of the link is in `guild`.
+    pub fn within(&self, guild: GuildId) -> bool {
+        self.guild == guild || self.to_guild() == guild
+    }
+    /// Whether the link joins `a` and `b`, in any direction.
+    pub fn between(&self, a: GuildId, b: GuildId) -> bool {
+        (self.guild, self.to_guild()) == (a, b) || (self.guild, self.to_guild()) == (b, a)
+    }
+    pub fn touches(&self, cid: ChannelId) -> bool {
+        self.from == cid || self.to == cid
+    }
+    fn link(&self) -> Link {
+        Link::new(self.from, self.to, self.mode())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SavedJitter {
+    pub guild: GuildId,
+    pub from: ChannelId,
+    pub to: ChannelId,
+    #[serde(flatten)]
+    pub config: JitterConfig,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SavedSpeakers {
+    pub guild: GuildId,
+    pub from: ChannelId,
+    pub to: ChannelId,
+    #[serde(flatten)]
+    pub filter: SpeakerFilter,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SavedBroadcast {
+    pub guild: GuildId,
+    #[serde(flatten)]
+    pub broadcast: Broadcast,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SavedRole {
+    pub guild: GuildId,
+    pub role: RoleId,
+}
+
+/// `guild` agrees to links with channels of `peer`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SavedPeer {
+    pub guild: GuildId,
+    pub peer: GuildId,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SavedVolume {
+    pub guild: GuildId,
+    pub user: u64,
+    pub db: f32,
+}
+
+/// Everything needed to rebuild the bridge topology after a restart.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct State {
+    #[serde(default)]
+    pub joins: Vec<SavedJoin>,
+    #[serde(default)]
+    pub links: Vec<SavedLink>,
+    #[serde(default)]
+    pub volumes: Vec<SavedVolume>,
+    /// jitter buffers differing from the default, per direction of a link.
+    #[serde(default)]
+    pub jitter: Vec<SavedJitter>,
+    /// speaker filters that are not empty, per direction of a link.
+    #[serde(default)]
+    pub speakers: Vec<SavedSpeakers>,
+    /// broadcast groups, whose links are not saved with the others.
+    #[serde(default)]
+    pub broadcasts: Vec<SavedBroadcast>,
+    /// roles allowed to run bridge commands, see [`crate::auth`].
+    #[serde(default)]
+    pub roles: Vec<SavedRole>,
+    /// guilds allowed to link with another, both sides have to agree.
+    #[serde(default)]
+    pub peers: Vec<SavedPeer>,
+}
+
+impl State {
+    pub fn add_join(&mut self, guild: GuildId, channel: ChannelId) {
+        let join = SavedJoin { guild, channel };
+        if !self.joins.contains(&join) {
+            self.joins.push(join);
+        }
+    }
+    pub fn remove_join(&mut self, guild: GuildId, channel: ChannelId) {
+        self.joins
+            .retain(|j| (j.guild, j.channel) != (guild, channel));
+    }
+    /// Saves `link` the way [`RoutingGraph::insert`] keeps it.
+    ///
+    /// Links are saved one by one rather than copied from the graph, so that
+    /// links of a suspended channel survive until it is resumed.
+    ///
+    /// [`RoutingGraph::insert`]: crate::routing::RoutingGraph::insert
+    pub fn add_link(&mut self, guild: GuildId, to_guild: GuildId, link: &Link) {
+        match link.mode {
+            LinkMode::OneWay => {
+                let covered = self
+                    .links
+                    .iter()
+                    .any(|l| l.within(guild) && l.link().routes(link.from_id, link.to_id));
+                if covered {
+                    return;
+                }
+            }
+            LinkMode::Bidirectional => self
+                .links
+                .retain(|l| !l.within(guild) || !l.link().joins(link.from_id, link.to_id)),
+        }
+        self.links.push(SavedLink {
+            guild,
+            from: link.from_id,
+            to_guild: (to_guild != guild).then_some(to_guild),
+            to: link.to_id,
+            bidirectional: link.mode == LinkMode::Bidirectional,
+        });
+    }
+    pub fn remove_link(&mut self, guild: GuildId, link: &Link) {
+        self.links.retain(|l| !l.within(guild) || l.link() != *link);
+        self.jitter.retain(|j| !link.joins(j.from, j.to));
+        self.speakers.retain(|f| !link.joins(f.from, f.to));
+    }
+    /// Forgets the links and broadcasts of `channel`, see
+    /// [`RoutingGraph::remove_from_broadcasts`].
+    ///
+    /// [`RoutingGraph::remove_from_broadcasts`]: crate::routing::RoutingGraph::remove_from_broadcasts
+    pub fn remove_links_of(&mut self, guild: GuildId, channel: ChannelId) {
+        self.links
+            .retain(|l| !l.within(guild) || !l.touches(channel));
+        self.jitter.retain(|j| j.from != channel && j.to != channel);
+        self.speakers
+            .retain(|f| f.from != channel && f.to != channel);
+        self.broadcasts
+            .retain(|b| (b.guild, b.broadcast.source) != (guild, channel));
+        for b in self.broadcasts.iter_mut().filter(|b| b.guild == guild) {
+            b.broadcast.listeners.retain(|l| *l != channel);
+        }
+    }
+    pub fn set_jitter(
+        &mut self,
+        guild: GuildId,
+        from: ChannelId,
+        to: ChannelId,
+        config: JitterConfig,
+    ) {
+        self.jitter
+            .retain(|j| (j.guild, j.from, j.to) != (guild, from, to));
+        if config != JitterConfig::default() {
+            self.jitter.push(SavedJitter {
+                guild,
+                from,
+                to,
+                config,
+            });
+        }
+    }
+    pub fn jitter(&self, guild: GuildId, from: ChannelId, to: ChannelId) -> JitterConfig {
+        self.jitter
+            .iter()
+            .find(|j| (j.guild, j.from, j.to) == (guild, from, to))
+            .map(|j| j.config)
+            .unwrap_or_default()
+    }
+    pub fn set_speakers(
+        &mut self,
+        guild: GuildId,
+        from: ChannelId,
+        to: ChannelId,
+        filter: SpeakerFilter,
+    ) {
+        // channel ids are unique, so either guild of a link sets the same filter
+        self.speakers.retain(|f| (f.from, f.to) != (from, to));
+        if !filter.is_empty() {
+            self.speakers.push(SavedSpeakers {
+                guild,
+                from,
+                to,
+                filter,
+            });
+        }
+    }
+    pub fn speakers(&self, from: ChannelId, to: ChannelId) -> SpeakerFilter {
+        self.speakers
+            .iter()
+            .find(|f| (f.from, f.to) == (from, to))
+            .map(|f| f.filter.clone())
+            .unwrap_or_default()
+    }
+    /// Saves `broadcast`, replacing the one of the same name.
+    pub fn set_broadcast(&mut self, guild: GuildId, broadcast: &Broadcast) {
+        self.remove_broadcast(guild, &broadcast.name);
+        self.broadcasts.push(SavedBroadcast {
+            guild,
+            broadcast: broadcast.clone(),
+        });
+    }
+    pub fn remove_broadcast(&mut self, guild: GuildId, name: &str) {
+        self.broadcasts
+            .retain(|b| (b.guild, b.broadcast.name.as_str()) != (guild, name));
+    }
+    /// `false` if `role` already was a bridge role.
+    pub fn add_role(&mut self, guild: GuildId, role: RoleId) -> bool {
+        let role = SavedRole { guild, role };
+        if self.roles.contains(&role) {
+            return false;
+        }
+        self.roles.push(role);
+        true
+    }
+    /// `false` if `role` was not a bridge role.
+    pub fn remove_role(&mut self, guild: GuildId, role: RoleId) -> bool {
+        let len = self.roles.len();
+        self.roles.retain(|r| (r.guild, r.role) != (guild, role));
+        self.roles.len() != len
+    }
+    /// `false` if `guild` already allowed links with `peer`.
+    pub fn add_peer(&mut self, guild: GuildId, peer: GuildId) -> bool {
+        let peer = SavedPeer { guild, peer };
+        if self.peers.contains(&peer) {
+            return false;
+        }
+        self.peers.push(peer);
+        true
+    }
+    /// Withdraws consent and forgets the saved links between the two guilds,
+    /// `false` if there was none.
+    pub fn remove_peer(&mut self, guild: GuildId, peer: GuildId) -> bool {
+        let len = self.peers.len();
+        self.peers.retain(|p| (p.guild, p.peer) != (guild, peer));
+        self.links.retain(|l| !l.between(guild, peer));
+        self.peers.len() != len
+    }
+    /// Whether channels of `a` and `b` may be linked: always within a guild,
+    /// across guilds only once both agreed.
+    pub fn peered(&self, a: GuildId, b: GuildId) -> bool {
+        let agrees = |guild, peer| self.peers.contains(&SavedPeer { guild, peer });
+        a == b || (agrees(a, b) && agrees(b, a))
+    }
+    pub fn set_volume(&mut self, guild: GuildId, user: UserId, gain: Option<Gain>) {
+        self.volumes
+            .retain(|v| (v.guild, v.user) != (guild, user.0));
+        if let Some(gain) = gain {
+            self.volumes.push(SavedVolume {
+                guild,
+                user: user.0,
+                db: gain.db(),
+            });
+        }
+    }
+}
+
+/// A JSON file holding the [`State`], rewritten on every change.
+#[derive(Debug)]
+pub struct Storage {
+    path: PathBuf,
+    state: Mutex<State>,
+}
+
+impl Storage {
+    /// Loads `path`, starting empty if it does not exist yet.
+    pub fn open(path: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let path = path.into();
+        let state = match std::fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => State::default(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            path,
+            state: Mutex::new(state),
+        })
+    }
+
+    pub async fn state(&self) -> State {
+        self.state.lock().await.clone()
+    }
+
+    pub async fn roles(&self, guild: GuildId) -> Vec<RoleId> {
+        self.state
+            .lock()
+            .await
+            .roles
+            .iter()
+            .filter(|r| r.guild == guild)
+            .map(|r| r.role)
+            .collect()
+    }
+
+    /// Guilds `guild` agreed to link with.
+    pub async fn peers(&self, guild: GuildId) -> Vec<GuildId> {
+        self.state
+            .lock()
+            .await
+            .peers
+            .iter()
+            .filter(|p| p.guild == guild)
+            .map(|p| p.peer)
+            .collect()
+    }
+
+    pub async fn peered(&self, a: GuildId, b: GuildId) -> bool {
+        self.state.lock().await.peered(a, b)
+    }
+
+    pub async fn jitter(&self, guild: GuildId, from: ChannelId, to: ChannelId) -> JitterConfig {
+        self.state.lock().await.jitter(guild, from, to)
+    }
+
+    pub async fn speakers(&self, from: ChannelId, to: ChannelId) -> SpeakerFilter {
+        self.state.lock().await.speakers(from, to)
+    }
+
+    /// Applies `f` and writes the result to disk.
+    pub async fn update<T>(&self, f: impl FnOnce(&mut State) -> T) -> T {
+        let mut state = self.state.lock().await;
+        let res = f(&mut state);
+        if let Err(e) = self.write(&state).await {
+            tracing::warn!("failed to save state to {}: {}", self.path.display(), e);
+        }
+        res
+    }
+
+    async fn write(&self, state: &State) -> anyhow::Result<()> {
+        let data = serde_json::to_vec_pretty(state)?;
+        // write then rename, so a crash never leaves a half written file
+        let tmp = self.path.with_extension("tmp");
+        tokio::fs::write(&tmp, data).await?;
+        tokio::fs::rename(&tmp, &self.path).await?;
+        Ok(())
+    }
+
+    /// Fills `volume_map` with the saved per-user gains.
+    pub async fn load_volumes(&self, volume_map: &GlobalVolumeMap) {
+        for v in self.state.lock().await.volumes.iter() {
+            volume_map
+                .entry(v.guild)
+                .or_default()
+                .insert(UserId(v.user), Gain::from_db(v.db));
+        }
+    }
+
+    /// Re-joins every saved channel, then re-creates the saved links.
+    pub async fn replay(&self, commands: &mpsc::Sender<AudioCommand>) {
+        let state = self.state().await;
+        for j in state.joins {
+            let payload = AudioCommandPayload::Join(j.guild, j.channel);
+            if let Err(e) = request(commands, payload).await {
+                tracing::warn!("failed to rejoin {}: {}", j.channel, e);
+            }
+        }
+        for l in state.links {
+            let payload = AudioCommandPayload::Connect {
+                gid: l.guild,
+                from_id: l.from,
+                to_gid: l.to_guild(),
+                to_id: l.to,
+                mode: l.mode(),
+            };
+            if let Err(e) = request(commands, payload).await {
+                tracing::warn!("failed to relink {} to {}: {}", l.from, l.to, e);
+            }
+        }
+        for b in state.broadcasts {
+            let (gid, name) = (b.guild, b.broadcast.name);
+            let payload = AudioCommandPayload::StartBroadcast {
+                gid,
+                name: name.clone(),
+                source: b.broadcast.source,
+            };
+            if let Err(e) = request(commands, payload).await {
+                tracing::warn!("failed to restart broadcast {}: {}", name, e);
+                continue;
+            }
+            for cid in b.broadcast.listeners {
+                let payload = AudioCommandPayload::AddListener {
+                    gid,
+                    name: name.clone(),
+                    cid,
+                };
+                if let Err(e) = request(commands, payload).await {
+                    tracing::warn!("failed to add {} to broadcast {}: {}", cid, name, e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::TempState;
+
+    const GUILD: GuildId = GuildId::new(1);
+    const PEER: GuildId = GuildId::new(2);
+    const A: ChannelId = ChannelId::new(10);
+    const B: ChannelId = ChannelId::new(11);
+    const C: ChannelId = ChannelId::new(12);
+    const D: ChannelId = ChannelId::new(13);
+    const FAR: ChannelId = ChannelId::new(20);
+
+    /// What [`Storage::replay`] asks of the audio service, answering `Ok`.
+    async fn replayed(storage: &Storage) -> Vec<String> {
+        let (tx, mut rx) = mpsc::channel(1);
+        let asked = tokio::spawn(async move {
+            let mut asked = Vec::new();
+            while let Some(AudioCommand { payload, tx }) = rx.recv().await {
+                asked.push(match payload {
+                    AudioCommandPayload::Join(gid, cid) => format!("join {}/{}", gid, cid),
+                    AudioCommandPayload::Connect {
+                        gid,
+                        from_id,
+                        to_gid,
+                        to_id,
+                        mode,
+                    } => format!("link {}/{} {}/{} {:?}", gid, from_id, to_gid, to_id, mode),
+                    AudioCommandPayload::StartBroadcast { name, source, .. } => {
+                        format!("broadcast {} from {}", name, source)
+                    }
+                    AudioCommandPayload::AddListener { name, cid, .. } => {
+                        format!("broadcast {} to {}", name, cid)
+                    }
+                    _ => panic!("not replayed"),
+                });
+                let _ = tx.send(Ok(()));
+            }
+            asked
+        });
+        storage.replay(&tx).await;
+        drop(tx);
+        asked.await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn saved_state_is_reloaded_and_replayed() {
+        let state = TempState::new("storage");
+        let storage = state.open();
+        storage
+            .update(|s| {
+                for cid in [A, B, A, C, D] {
+                    s.add_join(GUILD, cid);
+                }
+                s.remove_join(GUILD, D);
+                // one way both ways, then merged into one bidirectional link
+                s.add_link(GUILD, GUILD, &Link::new(A, B, LinkMode::OneWay));
+                s.add_link(GUILD, GUILD, &Link::new(B, A, LinkMode::OneWay));
+                s.add_link(GUILD, GUILD, &Link::new(A, B, LinkMode::Bidirectional));
+                s.add_link(GUILD, GUILD, &Link::new(B, A, LinkMode::OneWay));
+                s.add_link(GUILD, GUILD, &Link::new(B, C, LinkMode::OneWay));
+                s.remove_link(GUILD, &Link::new(B, C, LinkMode::OneWay));
+                s.add_link(GUILD, PEER, &Link::new(C, FAR, LinkMode::OneWay));
+                let mut news = Broadcast::new("news", C);
+                news.listeners.push(D);
+                s.set_broadcast(GUILD, &news);
+                s.set_volume(GUILD, UserId(100), Some(Gain::from_db(-6.0)));
+                s.set_volume(GUILD, UserId(101), Some(Gain::from_db(3.0)));
+                s.set_volume(GUILD, UserId(101), None);
+            })
+            .await;
+        assert!(!state.path().with_extension("tmp").exists());
+
+        let saved = state.open().state().await;
+        let joins: Vec<_> = saved.joins.iter().map(|j| j.channel).collect();
+        assert_eq!(joins, [A, B, C]);
+        assert_eq!(
+            saved.links,
+            [
+                SavedLink {
+                    guild: GUILD,
+                    from: A,
+                    to_guild: None,
+                    to: B,
+                    bidirectional: true,
+                },
+                SavedLink {
+                    guild: GUILD,
+                    from: C,
+                    to_guild: Some(PEER),
+                    to: FAR,
+                    bidirectional: false,
+                },
+            ]
+        );
+        assert_eq!(
+            saved.volumes,
+            [SavedVolume {
+                guild: GUILD,
+                user: 100,
+                db: -6.0,
+            }]
+        );
+
+        let storage = state.open();
+        let volume_map: GlobalVolumeMap = Default::default();
+        storage.load_volumes(&volume_map).await;
+        let gain = volume_map
+            .get(&GUILD)
+            .unwrap()
+            .get(&UserId(100))
+            .map(|g| *g);
+        assert_eq!(gain, Some(Gain::from_db(-6.0)));
+        assert_eq!(
+            replayed(&storage).await,
+            [
+                "join 1/10",
+                "join 1/11",
+                "join 1/12",
+                "link 1/10 1/11 Bidirectional",
+                "link 1/12 2/20 OneWay",
+                "broadcast news from 12",
+                "broadcast news to 13",
+            ]
+        );
+    }
+}
@@ -0,0 +1,66 @@
+use std::time::Duration;
+
+/// Delay before the first attempt to rejoin a failed call.
+const FIRST_DELAY: Duration = Duration::from_millis(500);
+
+/// Longest delay between two attempts.
+const MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// Attempts made before giving a call up, their delays adding up to 151.5 s.
+const MAX_ATTEMPTS: u32 = 10;
+
+/// Exponential backoff between attempts to rejoin a call whose voice driver
+/// failed.
+///
+/// The first attempt comes quickly, so a short outage is a short gap in the
+/// bridge; later ones back off so a region that is down is not hammered.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new() -> Self {
+        Self { attempt: 0 }
+    }
+
+    /// Number of the attempt the last [`Iterator::next`] delay was for.
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Iterator for Backoff {
+    type Item = Duration;
+
+    /// Delay before the next attempt, `None` once there should be none.
+    fn next(&mut self) -> Option<Duration> {
+        if self.attempt >= MAX_ATTEMPTS {
+            return None;
+        }
+        let delay = FIRST_DELAY.saturating_mul(1 << self.attempt.min(16));
+        self.attempt += 1;
+        Some(delay.min(MAX_DELAY))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delays_double_up_to_the_cap() {
+        let delays: Vec<_> = Backoff::new().map(|d| d.as_millis()).collect();
+        assert_eq!(
+            delays,
+            [500, 1000, 2000, 4000, 8000, 16000, 30000, 30000, 30000, 30000]
+        );
+        assert_eq!(delays.iter().sum::<u128>(), 151_500);
+    }
+}
@@ -1,21 +1,40 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use serenity::model::id::{ChannelId, GuildId};
 use tokio::sync::{mpsc, oneshot};
 
-use crate::audio::{AudioCommand, AudioCommandError, AudioCommandPayload, GlobalVolumeMap};
+use crate::audio::{request, AudioCommand, AudioCommandError, AudioCommandPayload, BotStatus, GlobalVolumeMap};
+use crate::pool::SharedBotPool;
+use crate::routing::SharedRoutingGraph;
+use crate::storage::Storage;
 
 #[derive(Debug, Clone)]
 pub struct Data{
     audiocommand: mpsc::Sender<AudioCommand>,
-    _volume_map: GlobalVolumeMap
+    pub volume_map: GlobalVolumeMap,
+    pub routes: SharedRoutingGraph,
+    pub storage: Arc<Storage>,
+    pub pool: SharedBotPool,
 }
 
 impl Data {
-    pub fn new(audiocommand: mpsc::Sender<AudioCommand>, volume_map: GlobalVolumeMap) -> Self{
-        Self { audiocommand, _volume_map: volume_map}
+    pub fn new(audiocommand: mpsc::Sender<AudioCommand>, volume_map: GlobalVolumeMap, routes: SharedRoutingGraph, storage: Arc<Storage>, pool: SharedBotPool) -> Self{
+        Self { audiocommand, volume_map, routes, storage, pool}
     }
     pub async fn command(&self, payload: AudioCommandPayload) -> Result<(), AudioCommandError> {
-        let (tx, rx) = oneshot::channel();
-        self.audiocommand.send(AudioCommand{payload, tx }).await.or_else(|_| Err(AudioCommandError::ProviderDropped))?;
-        rx.await.or_else(|_| Err(AudioCommandError::ProviderDropped))?
+        request(&self.audiocommand, payload).await
+    }
+    pub async fn status(&self, gid: GuildId) -> Result<Vec<BotStatus>, AudioCommandError> {
+        let (reply, rx) = oneshot::channel();
+        self.command(AudioCommandPayload::Status { gid, reply }).await?;
+        rx.await.map_err(|_| AudioCommandError::ProviderDropped)
+    }
+    /// Ends the recording of `cid`, giving the directory it was saved to.
+    pub async fn stop_recording(&self, gid: GuildId, cid: ChannelId) -> Result<PathBuf, AudioCommandError> {
+        let (reply, rx) = oneshot::channel();
+        self.command(AudioCommandPayload::StopRecording { gid, cid, reply }).await?;
+        rx.await.map_err(|_| AudioCommandError::ProviderDropped)
     }
 }
 
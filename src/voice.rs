@@ -0,0 +1,224 @@
+//! What the bridge needs from Discord voice, and its songbird and serenity
+//! implementations.
+//!
+//! [`crate::audio`] only talks to these traits, so it runs the same against
+//! a real gateway and against an in-process stand-in.
+
+use std::fmt::Debug;
+use std::sync::{Arc, Weak};
+
+use serenity::all::Cache;
+use serenity::async_trait;
+use serenity::model::id::{ChannelId, GuildId};
+use serenity_voice_model::id::UserId;
+use serenity_voice_model::payload::ClientDisconnect;
+use songbird::model::payload::Speaking;
+use songbird::tracks::TrackHandle;
+use songbird::{Call, CoreEvent, Event, EventContext, Songbird};
+use tokio::sync::Mutex;
+
+use crate::audio::VoiceEventHandler;
+use crate::mixer::MixerRx;
+use crate::passthrough::{opus_payload, OpusRx};
+
+/// One bot's voice connections, a call per guild.
+#[async_trait]
+pub trait VoiceDriver: Send + Sync {
+    /// Joins `cid`, moving the bot's call in `gid` if it has one.
+    async fn join(&self, gid: GuildId, cid: ChannelId) -> anyhow::Result<Arc<dyn VoiceCall>>;
+    /// Hangs up the call in `gid`.
+    async fn leave(&self, gid: GuildId) -> anyhow::Result<()>;
+}
+
+/// A handle to one call, which may have gone away since.
+#[async_trait]
+pub trait VoiceCall: Send + Sync + Debug {
+    /// Sends every [`VoiceEvent`] of the call to `handler`.
+    async fn subscribe(&self, handler: VoiceEventHandler);
+    async fn unsubscribe(&self);
+    /// Starts playing `source`, `None` if the call is gone.
+    async fn play(&self, source: TrackSource) -> Option<Box<dyn VoiceTrack>>;
+}
+
+/// A track playing in a call.
+pub trait VoiceTrack: Send + Sync + Debug {
+    /// Identifies the track in logs.
+    fn id(&self) -> String;
+    fn stop(&self);
+}
+
+/// What a track plays.
+pub enum TrackSource {
+    Pcm(MixerRx),
+    Opus(OpusRx),
+}
+
+/// Something that happened in a call.
+#[derive(Debug)]
+pub enum VoiceEvent<'a> {
+    /// `user` sends its voice as `ssrc`.
+    Speaking {
+        ssrc: u32,
+        user: UserId,
+    },
+    ClientDisconnect {
+        user: UserId,
+    },
+    /// the voice received in the last 20 ms.
+    Tick(Vec<Heard<'a>>),
+    DriverReconnect {
+        server: String,
+    },
+    /// the connection is gone; without a reason we were asked to leave.
+    DriverDisconnect {
+        kind: String,
+        reason: Option<String>,
+    },
+}
+
+/// One SSRC's voice on a tick.
+#[derive(Debug)]
+pub struct Heard<'a> {
+    pub ssrc: u32,
+    /// laid out as [`PcmFormat::DISCORD_VOICE`](crate::pcm::PcmFormat::DISCORD_VOICE).
+    pub pcm: Option<&'a [i16]>,
+    /// the Opus packet `pcm` was decoded from.
+    pub opus: Option<Arc<[u8]>>,
+}
+
+/// Somebody in a voice channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VoiceMember {
+    pub user: UserId,
+    /// whether it is a bot account, ours or not.
+    pub bot: bool,
+}
+
+/// Who is in which voice channel.
+pub trait ChannelLookup: Send + Sync {
+    /// Members in `cid`, `None` if `gid` is unknown.
+    fn voice_members(&self, gid: GuildId, cid: ChannelId) -> Option<Vec<VoiceMember>>;
+}
+
+impl ChannelLookup for Cache {
+    fn voice_members(&self, gid: GuildId, cid: ChannelId) -> Option<Vec<VoiceMember>> {
+        let guild = self.guild(gid)?;
+        let members = guild
+            .voice_states
+            .values()
+            .filter(|vs| vs.channel_id == Some(cid))
+            .map(|vs| VoiceMember {
+                user: UserId(vs.user_id.get()),
+                bot: vs.member.as_ref().is_some_and(|m| m.user.bot),
+            })
+            .collect();
+        Some(members)
+    }
+}
+
+pub struct SongbirdDriver(pub Arc<Songbird>);
+
+#[async_trait]
+impl VoiceDriver for SongbirdDriver {
+    async fn join(&self, gid: GuildId, cid: ChannelId) -> anyhow::Result<Arc<dyn VoiceCall>> {
+        let call = self.0.join(gid, cid).await?;
+        Ok(Arc::new(SongbirdCall(Arc::downgrade(&call))))
+    }
+
+    async fn leave(&self, gid: GuildId) -> anyhow::Result<()> {
+        Ok(self.0.remove(gid).await?)
+    }
+}
+
+/// Songbird owns the call; holding it weakly lets it go once left.
+#[derive(Debug)]
+struct SongbirdCall(Weak<Mutex<Call>>);
+
+#[async_trait]
+impl VoiceCall for SongbirdCall {
+    async fn subscribe(&self, handler: VoiceEventHandler) {
+        let Some(call) = self.0.upgrade() else {
+            return;
+        };
+        let events = [
+            CoreEvent::SpeakingStateUpdate,
+            CoreEvent::ClientDisconnect,
+            CoreEvent::VoiceTick,
+            CoreEvent::RtcpPacket,
+            CoreEvent::RtpPacket,
+            CoreEvent::DriverReconnect,
+            CoreEvent::DriverDisconnect,
+        ];
+        let mut call = call.lock().await;
+        for e in events {
+            call.add_global_event(Event::Core(e), handler.clone())
+        }
+    }
+
+    async fn unsubscribe(&self) {
+        if let Some(call) = self.0.upgrade() {
+            call.lock().await.remove_all_global_events();
+        }
+    }
+
+    async fn play(&self, source: TrackSource) -> Option<Box<dyn VoiceTrack>> {
+        let call = self.0.upgrade()?;
+        let input = match source {
+            TrackSource::Pcm(rx) => rx.into_input(),
+            TrackSource::Opus(rx) => rx.into_input(),
+        };
+        let track = call.lock().await.play_input(input);
+        Some(Box::new(track))
+    }
+}
+
+impl VoiceTrack for TrackHandle {
+    fn id(&self) -> String {
+        self.uuid().to_string()
+    }
+
+    fn stop(&self) {
+        let _ = TrackHandle::stop(self);
+    }
+}
+
+#[async_trait]
+impl songbird::EventHandler for VoiceEventHandler {
+    async fn act(&self, ctx: &EventContext<'_>) -> Option<Event> {
+        let event = match ctx {
+            EventContext::SpeakingStateUpdate(Speaking {
+                ssrc,
+                user_id: Some(uid),
+                ..
+            }) => VoiceEvent::Speaking {
+                ssrc: *ssrc,
+                user: *uid,
+            },
+            EventContext::ClientDisconnect(ClientDisconnect { user_id, .. }) => {
+                VoiceEvent::ClientDisconnect { user: *user_id }
+            }
+            EventContext::VoiceTick(tick) => VoiceEvent::Tick(
+                tick.speaking
+                    .iter()
+                    .map(|(ssrc, voice)| Heard {
+                        ssrc: *ssrc,
+                        pcm: voice.decoded_voice.as_deref(),
+                        opus: voice.packet.as_ref().and_then(opus_payload),
+                    })
+                    .collect(),
+            ),
+            // EventContext::RtcpPacket(data) => {}
+            // EventContext::RtpPacket(packet) => {}
+            EventContext::DriverReconnect(data) => VoiceEvent::DriverReconnect {
+                server: data.server.to_string(),
+            },
+            EventContext::DriverDisconnect(data) => VoiceEvent::DriverDisconnect {
+                kind: format!("{:?}", data.kind),
+                reason: data.reason.map(|r| format!("{:?}", r)),
+            },
+            _ => return None,
+        };
+        self.event(event).await;
+        None
+    }
+}
@@ -0,0 +1,252 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use dashmap::{DashMap, DashSet};
+use serenity::all::{Context, EventHandler, Guild, UnavailableGuild, VoiceState};
+use serenity::async_trait;
+use serenity::model::id::{ChannelId, GuildId};
+use tokio::sync::mpsc;
+
+use crate::audio::{request, AudioCommand, AudioCommandPayload, BotUsers};
+use crate::storage::Storage;
+use crate::voice::ChannelLookup;
+
+/// Suspends bridged channels that stay empty of humans, and resumes them
+/// once somebody comes back.
+///
+/// Every bot of the pool sees the voice states of its own guilds only, so
+/// each client gets a [`VoiceStateWatcher::client`] handler. A guild shared
+/// by several bots is watched through the first of them to report it, the
+/// others ignoring its updates so nothing is checked twice.
+pub struct VoiceStateWatcher {
+    commands: mpsc::Sender<AudioCommand>,
+    storage: Arc<Storage>,
+    bot_users: BotUsers,
+    idle_timeout: Duration,
+    /// bumped on every change, so a pending idle timer knows it is stale.
+    generations: Arc<DashMap<ChannelId, u64>>,
+    resuming: Arc<DashSet<ChannelId>>,
+    /// the client each guild's voice states are taken from.
+    owners: DashMap<GuildId, usize>,
+}
+
+/// Feeds the voice states one client sees to the shared watcher.
+pub struct WatcherClient {
+    watcher: Arc<VoiceStateWatcher>,
+    idx: usize,
+}
+
+impl VoiceStateWatcher {
+    pub fn new(
+        commands: mpsc::Sender<AudioCommand>,
+        storage: Arc<Storage>,
+        bot_users: BotUsers,
+        idle_timeout: Duration,
+    ) -> Self {
+        Self {
+            commands,
+            storage,
+            bot_users,
+            idle_timeout,
+            generations: Default::default(),
+            resuming: Default::default(),
+            owners: Default::default(),
+        }
+    }
+
+    /// Event handler for the client of bot `idx`.
+    pub fn client(self: &Arc<Self>, idx: usize) -> WatcherClient {
+        WatcherClient {
+            watcher: Arc::clone(self),
+            idx,
+        }
+    }
+
+    /// Whether client `idx` watches `gid`, claiming the guild if no client
+    /// does yet.
+    fn watches(&self, gid: GuildId, idx: usize) -> bool {
+        *self.owners.entry(gid).or_insert(idx) == idx
+    }
+
+    /// Lets another client take `gid` over, once client `idx` lost it.
+    fn unwatch(&self, gid: GuildId, idx: usize) {
+        self.owners.remove_if(&gid, |_, owner| *owner == idx);
+    }
+
+    async fn check(&self, channels: &Arc<dyn ChannelLookup>, gid: GuildId, cid: ChannelId) {
+        let Some((humans, bridged)) = occupancy(&**channels, &self.bot_users, gid, cid) else {
+            return;
+        };
+        if bridged {
+            let generation = {
+                let mut g = self.generations.entry(cid).or_default();
+                *g += 1;
+                *g
+            };
+            if humans == 0 {
+                self.suspend_later(Arc::clone(channels), gid, cid, generation);
+            }
+            return;
+        }
+        // only bridged channels are tracked; this also stales any pending timer
+        self.generations.remove(&cid);
+        if humans > 0 && self.is_saved(gid, cid).await {
+            if !self.resuming.insert(cid) {
+                return;
+            }
+            tracing::info!(guild = %gid, channel = %cid, "resuming");
+            let res = request(&self.commands, AudioCommandPayload::Resume(gid, cid)).await;
+            if let Err(e) = res {
+                tracing::warn!(guild = %gid, channel = %cid, "failed to resume: {}", e);
+            }
+            self.resuming.remove(&cid);
+        }
+    }
+
+    fn suspend_later(
+        &self,
+        channels: Arc<dyn ChannelLookup>,
+        gid: GuildId,
+        cid: ChannelId,
+        generation: u64,
+    ) {
+        let commands = self.commands.clone();
+        let bot_users = Arc::clone(&self.bot_users);
+        let generations = Arc::clone(&self.generations);
+        let timeout = self.idle_timeout;
+        tokio::spawn(async move {
+            tokio::time::sleep(timeout).await;
+            if generations.get(&cid).map(|g| *g) != Some(generation) {
+                return;
+            }
+            if occupancy(&*channels, &bot_users, gid, cid) != Some((0, true)) {
+                return;
+            }
+            tracing::info!(guild = %gid, channel = %cid, "suspending idle channel");
+            if let Err(e) = request(&commands, AudioCommandPayload::Suspend(gid, cid)).await {
+                tracing::warn!(guild = %gid, channel = %cid, "failed to suspend: {}", e);
+            }
+        });
+    }
+
+    async fn is_saved(&self, gid: GuildId, cid: ChannelId) -> bool {
+        self.storage
+            .state()
+            .await
+            .joins
+            .iter()
+            .any(|j| (j.guild, j.channel) == (gid, cid))
+    }
+}
+
+/// Humans in `cid` and whether one of our bots is there too.
+fn occupancy(
+    channels: &dyn ChannelLookup,
+    bot_users: &BotUsers,
+    gid: GuildId,
+    cid: ChannelId,
+) -> Option<(usize, bool)> {
+    let mut humans = 0;
+    let mut bridged = false;
+    for member in channels.voice_members(gid, cid)? {
+        if bot_users.contains(&member.user) {
+            bridged = true;
+        } else if !member.bot {
+            humans += 1;
+        }
+    }
+    Some((humans, bridged))
+}
+
+#[async_trait]
+impl EventHandler for WatcherClient {
+    async fn voice_state_update(&self, ctx: Context, old: Option<VoiceState>, new: VoiceState) {
+        let Some(gid) = new.guild_id else {
+            return;
+        };
+        if !self.watcher.watches(gid, self.idx) {
+            return;
+        }
+        let channels: Arc<dyn ChannelLookup> = ctx.cache;
+        let before = old.and_then(|vs| vs.channel_id);
+        if let Some(cid) = before.filter(|cid| Some(*cid) != new.channel_id) {
+            self.watcher.check(&channels, gid, cid).await;
+        }
+        if let Some(cid) = new.channel_id {
+            self.watcher.check(&channels, gid, cid).await;
+        }
+    }
+
+    async fn guild_delete(&self, _: Context, guild: UnavailableGuild, _: Option<Guild>) {
+        self.watcher.unwatch(guild.id, self.idx);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serenity_voice_model::id::UserId;
+
+    use crate::sim::{bot_user, Sim, TempState};
+
+    #[tokio::test]
+    async fn occupancy_tells_humans_from_bots() {
+        let (gid, cid) = (GuildId::new(1), ChannelId::new(10));
+        let sim = Sim::new();
+        let bot_users: BotUsers = Default::default();
+        bot_users.insert(bot_user(0));
+        assert_eq!(occupancy(&sim, &bot_users, gid, cid), Some((0, false)));
+
+        sim.driver(0).join(gid, cid).await.unwrap();
+        assert_eq!(occupancy(&sim, &bot_users, gid, cid), Some((0, true)));
+
+        sim.enter(gid, cid, UserId(100), 1).await;
+        assert_eq!(occupancy(&sim, &bot_users, gid, cid), Some((1, true)));
+    }
+
+    #[tokio::test]
+    async fn only_bridged_channels_are_tracked() {
+        let (gid, cid) = (GuildId::new(1), ChannelId::new(10));
+        let sim = Sim::new();
+        let channels: Arc<dyn ChannelLookup> = Arc::new(sim.clone());
+        let state = TempState::new("watcher");
+        let storage = state.open();
+        let bot_users: BotUsers = Default::default();
+        bot_users.insert(bot_user(0));
+        let (commands, _rx) = mpsc::channel(1);
+        let watcher =
+            VoiceStateWatcher::new(commands, storage, bot_users, Duration::from_secs(3600));
+
+        sim.enter(gid, cid, UserId(100), 1).await;
+        watcher.check(&channels, gid, cid).await;
+        assert!(watcher.generations.is_empty());
+
+        sim.driver(0).join(gid, cid).await.unwrap();
+        watcher.check(&channels, gid, cid).await;
+        assert!(watcher.generations.contains_key(&cid));
+
+        sim.driver(0).leave(gid).await.unwrap();
+        watcher.check(&channels, gid, cid).await;
+        assert!(watcher.generations.is_empty());
+    }
+    #[test]
+    fn each_guild_is_watched_through_one_client() {
+        let state = TempState::new("watcher-owners");
+        let (commands, _rx) = mpsc::channel(1);
+        let watcher =
+            VoiceStateWatcher::new(commands, state.open(), Default::default(), Duration::ZERO);
+        let (first, second) = (GuildId::new(1), GuildId::new(2));
+
+        assert!(watcher.watches(first, 1));
+        assert!(!watcher.watches(first, 0));
+        assert!(watcher.watches(second, 0));
+        assert!(!watcher.watches(second, 1));
+
+        // only the watching client giving the guild up hands it over
+        watcher.unwatch(first, 0);
+        assert!(!watcher.watches(first, 0));
+        watcher.unwatch(first, 1);
+        assert!(watcher.watches(first, 0));
+        assert!(!watcher.watches(first, 1));
+    }
+}